@@ -1,3 +1,5 @@
 rm fs.db
-script="$(cat src/local/create_schema.sql)"
-sqlite3 fs.db "$script"
\ No newline at end of file
+for file in src/local/migrations/*.sql; do
+    script="$(cat $file)"
+    sqlite3 fs.db "$script"
+done
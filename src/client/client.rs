@@ -1,24 +1,84 @@
+use std::path::PathBuf;
+
 use async_trait::async_trait;
 
 use crate::{
-    local::{db::FsNode, error::FsError},
-    util::async_file::{AsyncRead, AsyncWrite},
+    local::{
+        db::{FsChunk, FsNode},
+        error::FsError,
+    },
+    util::async_file::{AsyncRead, AsyncSeek, AsyncWrite},
 };
 
+/// How an existing file gets opened for writing
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WriteOptions {
+    /// Start from an empty file instead of the current content
+    pub truncate: bool,
+}
+
+/// Settings shared by every cloud client
+#[derive(Debug, Clone)]
+pub struct ClientOptions {
+    /// Directory writes are staged in until they are uploaded
+    pub staging_dir: PathBuf,
+    /// Directory downloaded chunks are cached in
+    pub cache_dir: PathBuf,
+    /// Most bytes the chunk cache takes up on disk, 0 to turn it off
+    pub cache_size: u64,
+    /// How many chunks past the one being read get downloaded in the background
+    pub read_ahead: usize,
+    /// Most bytes of downloaded chunks waiting to be read, across all open files
+    pub read_ahead_memory: u64,
+    /// Most chunks uploading at the same time, across all files
+    pub upload_parallelism: usize,
+    /// Attempts a request gets when it fails for a passing reason like a timeout or a 5xx
+    pub retry_attempts: usize,
+}
+
 #[async_trait]
 pub trait CloudClient: Send + Sync {
-    async fn open_file_write(&self, node: FsNode) -> Box<dyn CloudWrite>;
+    async fn open_file_write(
+        &self,
+        node: FsNode,
+        options: WriteOptions,
+    ) -> Result<Box<dyn CloudWrite>, FsError>;
     async fn open_file_read(&self, node: FsNode) -> Result<Box<dyn CloudRead>, FsError>;
+    /// Brings cloud metadata written by older versions up to date
+    async fn migrate(&self) -> Result<(), FsError>;
+    /// Size of the pieces files are stored in
+    fn block_size(&self) -> u64;
+    /// Deletes the uploaded content of chunks no node refers to anymore
+    async fn delete_chunks(&self, chunks: &[FsChunk]) -> Result<(), FsError>;
+    /// Size of a file whose writes haven't all been uploaded yet
+    async fn staged_size(&self, id: i64) -> Option<u64>;
+    /// Ids and sizes of every file whose writes haven't all been uploaded yet
+    async fn staged_sizes(&self) -> Vec<(i64, u64)>;
+    /// Continues uploads left unfinished when the filesystem was last unmounted
+    async fn resume(&self) -> Result<(), FsError>;
 }
 
-pub trait CloudWrite: AsyncWrite + Send + Sync {
+/// Writable file, which can also be read back including changes that aren't flushed yet
+#[async_trait]
+pub trait CloudWrite: AsyncWrite + AsyncRead + AsyncSeek + Send + Sync {
+    /// Current size including unflushed writes
+    fn size(&self) -> u64;
+
+    /// Truncates or extends the file with zeroes
+    async fn set_len(&mut self, size: u64) -> std::io::Result<()>;
+
+    /// Waits until everything written so far is stored in the cloud
+    async fn sync(&mut self) -> std::io::Result<()> {
+        self.flush().await
+    }
+
     /// Essentially a hook at the end of a write operation.
     /// Useful for logging
-    fn finish(&self) -> ();
+    fn finish(&self);
 }
 
-pub trait CloudRead: AsyncRead + Send + Sync {
+pub trait CloudRead: AsyncRead + AsyncSeek + Send + Sync {
     /// Essentially a hook at the end of a read operation.
     /// Useful for logging
-    fn finish(&self) -> ();
+    fn finish(&self);
 }
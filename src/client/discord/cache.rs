@@ -0,0 +1,275 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+    time::SystemTime,
+};
+
+use log::{debug, warn};
+use tokio::sync::Mutex;
+
+use crate::{client::error::ClientError, encryption::aes::Aes};
+
+/// Cached chunk and when it was last used
+struct CacheEntry {
+    size: u64,
+    used: u64,
+}
+
+/// Least recently used chunks first
+#[derive(Default)]
+struct CacheIndex {
+    entries: HashMap<String, CacheEntry>,
+    by_use: BTreeMap<u64, String>,
+    next_use: u64,
+    size: u64,
+}
+
+impl CacheIndex {
+    fn touch(&mut self, key: &str) {
+        let used = self.next_use;
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.by_use.remove(&entry.used);
+            entry.used = used;
+            self.by_use.insert(used, key.to_string());
+            self.next_use += 1;
+        }
+    }
+
+    fn insert(&mut self, key: String, size: u64) {
+        self.remove(&key);
+        let used = self.next_use;
+        self.next_use += 1;
+        self.by_use.insert(used, key.clone());
+        self.entries.insert(key, CacheEntry { size, used });
+        self.size += size;
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.by_use.remove(&entry.used);
+            self.size -= entry.size;
+        }
+    }
+
+    /// Drops the least recently used chunks until `size` fits, returning their keys
+    fn evict(&mut self, max_size: u64) -> Vec<String> {
+        let mut evicted = vec![];
+        while self.size > max_size {
+            let Some((_, key)) = self.by_use.pop_first() else {
+                break;
+            };
+            if let Some(entry) = self.entries.remove(&key) {
+                self.size -= entry.size;
+            }
+            evicted.push(key);
+        }
+        evicted
+    }
+}
+
+/// Downloaded chunks kept encrypted on local disk by attachment id, up to `max_size` bytes
+pub struct ChunkCache {
+    dir: PathBuf,
+    max_size: u64,
+    index: Mutex<CacheIndex>,
+}
+
+impl ChunkCache {
+    /// Opens the cache in `dir`, picking up chunks cached by earlier runs
+    pub fn new(dir: PathBuf, max_size: u64) -> Result<Self, ClientError> {
+        let mut index = CacheIndex::default();
+        if max_size > 0 {
+            std::fs::create_dir_all(&dir).map_err(|e| {
+                ClientError::Initialization(format!("creating cache dir {:?}: {}", dir, e))
+            })?;
+            let entries = std::fs::read_dir(&dir).map_err(|e| {
+                ClientError::Initialization(format!("reading cache dir {:?}: {}", dir, e))
+            })?;
+            // Chunks get their modification time bumped when read, so it orders them by use
+            let mut found = vec![];
+            for entry in entries.flatten() {
+                let name = entry.file_name().to_string_lossy().to_string();
+                let Ok(metadata) = entry.metadata() else {
+                    continue;
+                };
+                if name.ends_with(".tmp") {
+                    let _ = std::fs::remove_file(entry.path());
+                    continue;
+                }
+                let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                found.push((modified, name, metadata.len()));
+            }
+            found.sort();
+            for (_, name, size) in found {
+                index.insert(name, size);
+            }
+            // The limit may have been lowered since the last run
+            for key in index.evict(max_size) {
+                debug!("evicting cached chunk {}", key);
+                let _ = std::fs::remove_file(dir.join(key));
+            }
+        }
+        Ok(Self {
+            dir,
+            max_size,
+            index: Mutex::new(index),
+        })
+    }
+
+    fn enabled(&self) -> bool {
+        self.max_size > 0
+    }
+
+    /// Attachment ids are numbers, anything else would not make a safe file name
+    fn path(&self, key: &str) -> Option<PathBuf> {
+        if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return None;
+        }
+        Some(self.dir.join(key))
+    }
+
+    /// Cached content of a chunk, dropping it if it can't be read back
+    pub async fn get(&self, aes: &Aes, key: &str) -> Option<Vec<u8>> {
+        if !self.enabled() {
+            return None;
+        }
+        let path = self.path(key)?;
+        if !self.index.lock().await.entries.contains_key(key) {
+            return None;
+        }
+        let result = async {
+            let mut data = tokio::fs::read(&path).await?;
+            let len = aes.decrypt(&mut data)?.len();
+            data.truncate(len);
+            std::io::Result::Ok(data)
+        }
+        .await;
+        match result {
+            Ok(data) => {
+                debug!("cache hit for {}", key);
+                self.index.lock().await.touch(key);
+                if let Ok(file) = std::fs::File::options().write(true).open(&path) {
+                    let _ = file.set_modified(SystemTime::now());
+                }
+                Some(data)
+            }
+            Err(e) => {
+                warn!("dropping unreadable cached chunk {}: {:?}", key, e);
+                self.index.lock().await.remove(key);
+                let _ = tokio::fs::remove_file(&path).await;
+                None
+            }
+        }
+    }
+
+    /// Caches the content of a chunk, logging rather than returning errors
+    pub async fn put(&self, aes: &Aes, key: &str, data: &[u8]) {
+        let Some(path) = self.path(key) else {
+            return;
+        };
+        if !self.enabled() || data.len() as u64 > self.max_size {
+            return;
+        }
+        let result = async {
+            let mut buffer = data.to_vec();
+            aes.encrypt(&mut buffer)?;
+            let tmp = path.with_extension("tmp");
+            tokio::fs::write(&tmp, &buffer).await?;
+            tokio::fs::rename(&tmp, &path).await?;
+            std::io::Result::Ok(buffer.len() as u64)
+        }
+        .await;
+        let size = match result {
+            Ok(size) => size,
+            Err(e) => {
+                warn!("error caching chunk {}: {:?}", key, e);
+                return;
+            }
+        };
+        let evicted = {
+            let mut index = self.index.lock().await;
+            index.insert(key.to_string(), size);
+            index.evict(self.max_size)
+        };
+        for key in evicted {
+            debug!("evicting cached chunk {}", key);
+            if let Some(path) = self.path(&key) {
+                let _ = tokio::fs::remove_file(path).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::error::Error;
+
+    use crate::util::test::TempDir;
+
+    use super::*;
+
+    type TestResult = Result<(), Box<dyn Error>>;
+
+    fn aes() -> Aes {
+        Aes::new(&[7; 32]).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_cache_round_trip() -> TestResult {
+        let dir = TempDir::new("cache-round-trip");
+        let aes = aes();
+        let cache = ChunkCache::new(dir.0.clone(), 1 << 20)?;
+        assert!(cache.get(&aes, "1").await.is_none());
+
+        cache.put(&aes, "1", b"plain chunk").await;
+        assert_eq!(
+            cache.get(&aes, "1").await.as_deref(),
+            Some(&b"plain chunk"[..])
+        );
+        // Nothing is stored in the clear
+        let stored = std::fs::read(dir.0.join("1"))?;
+        assert!(!stored.windows(5).any(|w| w == b"plain"));
+
+        // Chunks survive a restart, but not being read with another key
+        let cache = ChunkCache::new(dir.0.clone(), 1 << 20)?;
+        assert!(cache.get(&Aes::new(&[8; 32])?, "1").await.is_none());
+        assert!(!dir.0.join("1").exists());
+
+        // Keys that aren't attachment ids never touch the disk
+        cache.put(&aes, "../escape", b"data").await;
+        assert!(cache.get(&aes, "../escape").await.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cache_eviction() -> TestResult {
+        let dir = TempDir::new("cache-eviction");
+        let aes = aes();
+        let data = vec![1; 100];
+        // Each stored chunk takes 128 bytes with the tag and nonce
+        let cache = ChunkCache::new(dir.0.clone(), 300)?;
+        cache.put(&aes, "1", &data).await;
+        cache.put(&aes, "2", &data).await;
+        assert!(cache.get(&aes, "1").await.is_some());
+
+        // The chunk read least recently makes room for the new one
+        cache.put(&aes, "3", &data).await;
+        assert!(cache.get(&aes, "2").await.is_none());
+        assert!(cache.get(&aes, "1").await.is_some());
+        assert!(cache.get(&aes, "3").await.is_some());
+        assert_eq!(std::fs::read_dir(&dir.0)?.count(), 2);
+
+        // Reopening with a lower limit drops what no longer fits
+        let cache = ChunkCache::new(dir.0.clone(), 200)?;
+        assert_eq!(std::fs::read_dir(&dir.0)?.count(), 1);
+        assert!(cache.get(&aes, "3").await.is_some());
+        assert!(cache.get(&aes, "1").await.is_none());
+
+        // A size of 0 turns the cache off
+        let disabled = ChunkCache::new(dir.0.join("off"), 0)?;
+        disabled.put(&aes, "1", &data).await;
+        assert!(disabled.get(&aes, "1").await.is_none());
+        assert!(!dir.0.join("off").exists());
+        Ok(())
+    }
+}
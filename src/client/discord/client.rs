@@ -1,23 +1,28 @@
 use std::sync::Arc;
 
 use async_trait::async_trait;
-use tokio::runtime::Handle;
+use log::{debug, info, warn};
+use ring::aead::{MAX_TAG_LEN, NONCE_LEN};
+use tokio::{runtime::Handle, sync::Semaphore};
 
 use crate::{
     client::{
-        client::{CloudClient, CloudRead, CloudWrite},
+        client::{ClientOptions, CloudClient, CloudRead, CloudWrite, WriteOptions},
         error::ClientError,
     },
     encryption::aes::Aes,
     local::{
-        db::{FsDatabase, FsNode},
+        db::{FsChunk, FsDatabase, FsNode},
         error::FsError,
     },
 };
 
 use super::{
-    file::{DiscordFileRead, DiscordFileWrite},
+    cache::ChunkCache,
+    file::{DiscordFileRead, DiscordFileWrite, DiscordStagedRead, DISCORD_CONTENT_SIZE},
     net::DiscordNetClient,
+    retry::RetryPolicy,
+    staging::{ChunkStore, Staging},
 };
 
 /// Virtual file host
@@ -25,34 +30,247 @@ pub struct DiscordClientInner {
     pub net: DiscordNetClient,
     pub db: Arc<FsDatabase>,
     pub aes: Aes,
+    pub staging: Staging,
+    pub cache: ChunkCache,
+    /// Chunks to fetch ahead of each reader
+    pub read_ahead: usize,
+    /// One per chunk that may be held in memory by read-ahead
+    pub read_ahead_permits: Arc<Semaphore>,
 }
 
 pub struct DiscordClient {
     inner: Arc<DiscordClientInner>,
 }
 
+impl DiscordClientInner {
+    /// Chunk table of a node.
+    /// Nodes uploaded before chunks were tracked get their reply chain walked once
+    pub async fn get_chunks(&self, node: &FsNode) -> Result<Vec<FsChunk>, FsError> {
+        let chunks = self.db.get_chunks(node.id).await?;
+        if chunks.is_empty() && node.cloud_id.is_some() {
+            return self.migrate_node(node).await;
+        }
+        Ok(chunks)
+    }
+
+    /// Downloads and decrypts a chunk unless it is cached
+    pub async fn download_chunk(&self, chunk: &FsChunk) -> Result<Vec<u8>, ClientError> {
+        if let Some(data) = self.cache.get(&self.aes, &chunk.attachment_id).await {
+            return Ok(data);
+        }
+        debug!("downloading id: {:?}", chunk.attachment_id);
+        let mut buffer: Vec<u8> = Vec::with_capacity(chunk.cipher_size as usize);
+        self.net
+            .download_file(&self.net.channel_id, &chunk.attachment_id, &mut buffer)
+            .await?;
+
+        // Decryption happens in place and leaves the tag at the end of the buffer
+        let decrypted_size = self.aes.decrypt(&mut buffer)?.len();
+        buffer.truncate(decrypted_size);
+        self.cache
+            .put(&self.aes, &chunk.attachment_id, &buffer)
+            .await;
+        Ok(buffer)
+    }
+
+    /// Encrypts and uploads a chunk. Chunks don't reply to each other since their order is
+    /// kept in the database, which lets chunks of one file upload at the same time.
+    /// Encryption works on a copy so the data is still there if the upload fails
+    pub async fn upload_chunk(
+        &self,
+        node: i64,
+        idx: usize,
+        data: &[u8],
+    ) -> Result<FsChunk, ClientError> {
+        let mut buffer = Vec::with_capacity(data.len() + MAX_TAG_LEN + NONCE_LEN);
+        buffer.extend_from_slice(data);
+        let encrypted_buffer = self.aes.encrypt(&mut buffer)?;
+        let uploaded = self
+            .net
+            .create_message(&self.net.channel_id, encrypted_buffer)
+            .await?;
+        // Files are often read again soon after they are written
+        self.cache
+            .put(&self.aes, &uploaded.attachment_id, data)
+            .await;
+        Ok(FsChunk {
+            node,
+            idx: idx as i64,
+            message_id: uploaded.message_id,
+            attachment_id: uploaded.attachment_id,
+            size: data.len() as i64,
+            cipher_size: uploaded.size as i64,
+        })
+    }
+
+    /// Deletes the messages of chunks.
+    /// Keeps going past failures so one bad message doesn't leave the rest behind
+    pub async fn delete_chunks(&self, chunks: &[FsChunk]) -> Result<(), ClientError> {
+        let mut failed = 0;
+        for chunk in chunks {
+            if let Err(e) = self
+                .net
+                .delete_message(&self.net.channel_id, &chunk.message_id)
+                .await
+            {
+                warn!("error deleting message {}: {:?}", chunk.message_id, e);
+                failed += 1;
+            }
+        }
+        if failed > 0 {
+            return Err(ClientError::RequestValue(format!(
+                "{} messages could not be deleted",
+                failed
+            )));
+        }
+        Ok(())
+    }
+
+    async fn migrate_node(&self, node: &FsNode) -> Result<Vec<FsChunk>, FsError> {
+        let Some(cloud_id) = node.cloud_id.as_ref() else {
+            return Ok(vec![]);
+        };
+        info!("migrating reply chain of node {} to chunk table", node.id);
+        let chunks: Vec<FsChunk> = self
+            .net
+            .get_file_chain(&self.net.channel_id, cloud_id)
+            .await?
+            .into_iter()
+            .enumerate()
+            .map(|(idx, chunk)| FsChunk {
+                node: node.id,
+                idx: idx as i64,
+                message_id: chunk.message_id,
+                attachment_id: chunk.attachment_id,
+                size: (chunk.size as usize).saturating_sub(MAX_TAG_LEN + NONCE_LEN) as i64,
+                cipher_size: chunk.size as i64,
+            })
+            .collect();
+        if self
+            .db
+            .set_migrated_chunks(node.id, cloud_id, &chunks)
+            .await?
+        {
+            return Ok(chunks);
+        }
+        // Written while the chain was walked, what is there now is newer
+        Ok(self.db.get_chunks(node.id).await?)
+    }
+}
+
 impl DiscordClient {
-    pub fn new(rt: Handle, db: Arc<FsDatabase>) -> Result<Self, ClientError> {
+    pub fn new(
+        rt: Handle,
+        db: Arc<FsDatabase>,
+        options: &ClientOptions,
+    ) -> Result<Self, ClientError> {
         let aes = Aes::from_env("SECRET_KEY")?;
         Ok(Self {
             inner: Arc::new(DiscordClientInner {
-                net: DiscordNetClient::new(rt)?,
+                net: DiscordNetClient::new(rt, RetryPolicy::new(options.retry_attempts))?,
                 db,
                 aes,
+                staging: Staging::new(
+                    options.staging_dir.clone(),
+                    DISCORD_CONTENT_SIZE as u64,
+                    options.upload_parallelism,
+                )?,
+                cache: ChunkCache::new(options.cache_dir.clone(), options.cache_size)?,
+                read_ahead: options.read_ahead,
+                read_ahead_permits: Arc::new(Semaphore::new(
+                    (options.read_ahead_memory / DISCORD_CONTENT_SIZE as u64) as usize,
+                )),
             }),
         })
     }
 }
 
+#[async_trait]
+impl ChunkStore for DiscordClientInner {
+    async fn get_chunks(&self, node: &FsNode) -> Result<Vec<FsChunk>, FsError> {
+        DiscordClientInner::get_chunks(self, node).await
+    }
+
+    async fn download_chunk(&self, chunk: &FsChunk) -> Result<Vec<u8>, ClientError> {
+        DiscordClientInner::download_chunk(self, chunk).await
+    }
+
+    async fn upload_chunk(
+        &self,
+        node: i64,
+        idx: usize,
+        data: &[u8],
+    ) -> Result<FsChunk, ClientError> {
+        DiscordClientInner::upload_chunk(self, node, idx, data).await
+    }
+
+    async fn discard_chunks(&self, chunks: &[FsChunk]) {
+        if let Err(e) = self.delete_chunks(chunks).await {
+            warn!("error discarding unused chunks: {:?}", e);
+        }
+    }
+
+    fn db(&self) -> &FsDatabase {
+        &self.db
+    }
+
+    fn staging(&self) -> &Staging {
+        &self.staging
+    }
+}
+
 #[async_trait]
 impl CloudClient for DiscordClient {
-    async fn open_file_write(&self, node: FsNode) -> Box<dyn CloudWrite> {
-        Box::new(DiscordFileWrite::new(self.inner.clone(), node))
+    async fn open_file_write(
+        &self,
+        node: FsNode,
+        options: WriteOptions,
+    ) -> Result<Box<dyn CloudWrite>, FsError> {
+        Ok(Box::new(
+            DiscordFileWrite::new(self.inner.clone(), node, options).await?,
+        ))
     }
 
     async fn open_file_read(&self, node: FsNode) -> Result<Box<dyn CloudRead>, FsError> {
+        // The database only has the new content once its upload is done
+        if let Some(staged) = self.inner.staging.get(node.id).await {
+            return Ok(Box::new(DiscordStagedRead::new(
+                self.inner.clone(),
+                node.id,
+                staged,
+            )));
+        }
         Ok(Box::new(
             DiscordFileRead::new(self.inner.clone(), node).await?,
         ))
     }
+
+    async fn migrate(&self) -> Result<(), FsError> {
+        for node in self.inner.db.get_unchunked_nodes().await? {
+            self.inner.migrate_node(&node).await?;
+        }
+        Ok(())
+    }
+
+    fn block_size(&self) -> u64 {
+        DISCORD_CONTENT_SIZE as u64
+    }
+
+    async fn delete_chunks(&self, chunks: &[FsChunk]) -> Result<(), FsError> {
+        Ok(self.inner.delete_chunks(chunks).await?)
+    }
+
+    async fn staged_size(&self, id: i64) -> Option<u64> {
+        let staged = self.inner.staging.get(id).await?;
+        let size = staged.lock().await.size();
+        Some(size)
+    }
+
+    async fn staged_sizes(&self) -> Vec<(i64, u64)> {
+        self.inner.staging.sizes().await
+    }
+
+    async fn resume(&self) -> Result<(), FsError> {
+        Staging::resume(self.inner.clone()).await
+    }
 }
@@ -1,62 +1,98 @@
-use std::{cmp::min, sync::Arc, time::SystemTime};
+use std::{cmp::min, io::SeekFrom, sync::Arc, time::SystemTime};
 
 use async_trait::async_trait;
 use log::{debug, info, trace};
 use ring::aead::{MAX_TAG_LEN, NONCE_LEN};
+use tokio::runtime::Handle;
 
 use crate::{
     client::{
-        client::{CloudRead, CloudWrite},
-        error::ClientError,
+        client::{CloudRead, CloudWrite, WriteOptions},
+        discord::staging::{spawn_upload, upload, SharedStaged},
     },
-    local::{db::FsNode, error::FsError},
-    util::async_file::{AsyncRead, AsyncWrite},
+    local::{
+        db::{FsChunk, FsNode},
+        error::FsError,
+    },
+    util::async_file::{AsyncRead, AsyncSeek, AsyncWrite},
 };
 
-use super::client::DiscordClientInner;
+use super::{client::DiscordClientInner, readahead::ReadAhead};
 
 pub const DISCORD_BLOCK_SIZE: usize = 25 * 1024 * 1024;
 pub const DISCORD_CONTENT_SIZE: usize = DISCORD_BLOCK_SIZE - MAX_TAG_LEN - NONCE_LEN;
 
-/// Virtual file hosted on Discord
+/// Virtual file hosted on Discord, open for writing.
+/// Writes land in a local staging file and get uploaded in the background once flushed
 pub struct DiscordFileWrite {
-    buffer: Vec<u8>,
+    id: i64,
+    staged: SharedStaged,
+    position: u64,
+    /// Size of the staged file as of the last operation through this writer
+    size: u64,
     total_size: i64,
-    node: FsNode,
-    prev_id: Option<String>,
     open_time: SystemTime,
     client: Arc<DiscordClientInner>,
 }
 
 impl DiscordFileWrite {
-    pub fn new(client: Arc<DiscordClientInner>, node: FsNode) -> Self {
-        DiscordFileWrite {
-            buffer: Vec::with_capacity(DISCORD_BLOCK_SIZE),
+    pub async fn new(
+        client: Arc<DiscordClientInner>,
+        node: FsNode,
+        options: WriteOptions,
+    ) -> Result<Self, FsError> {
+        let staged = client
+            .staging
+            .open(client.as_ref(), &node, options.truncate)
+            .await
+            .map_err(|e| FsError::RuntimeError(e.to_string()))?;
+        let size = staged.lock().await.size();
+        Ok(DiscordFileWrite {
+            id: node.id,
+            staged,
+            position: 0,
+            size,
             total_size: 0,
-            node,
-            prev_id: None,
             client,
             open_time: SystemTime::now(),
-        }
+        })
     }
+}
 
-    /// Uploads a buffer with encryption.
-    /// Internal buffer gets mutated in place so must be cleared to be reused
-    async fn upload_buffer(&mut self) -> Result<String, ClientError> {
-        // Encrypt buffer
-        let encrypted_buffer = self.client.aes.encrypt(&mut self.buffer)?;
-        let id = self
-            .client
-            .net
-            .create_message(&self.client.net.channel_id, encrypted_buffer, &self.prev_id)
-            .await?;
-        self.buffer.clear();
-        Ok(id)
+impl Drop for DiscordFileWrite {
+    fn drop(&mut self) {
+        // The staged file goes away once nothing uses it and its upload is done
+        if let Ok(rt) = Handle::try_current() {
+            let client = self.client.clone();
+            let id = self.id;
+            rt.spawn(async move { client.staging.cleanup(id).await });
+        }
     }
 }
 
+#[async_trait]
 impl CloudWrite for DiscordFileWrite {
-    fn finish(&self) -> () {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    async fn set_len(&mut self, size: u64) -> std::io::Result<()> {
+        let mut staged = self.staged.lock().await;
+        staged.resize(self.client.as_ref(), size).await?;
+        self.size = staged.size();
+        Ok(())
+    }
+
+    async fn sync(&mut self) -> std::io::Result<()> {
+        {
+            let mut staged = self.staged.lock().await;
+            staged.take_error();
+            self.client.staging.save(&staged).await?;
+        }
+        upload(self.client.clone(), &self.staged).await
+    }
+
+    fn finish(&self) {
         let time = self.open_time.elapsed().unwrap_or_default().as_secs_f64();
         info!(
             "wrote {} bytes in {}s ({} MiB/s)",
@@ -70,76 +106,177 @@ impl CloudWrite for DiscordFileWrite {
 #[async_trait]
 impl AsyncWrite for DiscordFileWrite {
     async fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut staged = self.staged.lock().await;
+        staged
+            .write_at(self.client.as_ref(), self.position, buf)
+            .await?;
+        self.position += buf.len() as u64;
+        self.size = staged.size();
         self.total_size += buf.len() as i64;
+        Ok(buf.len())
+    }
+
+    /// Hands the staged changes to the background uploader.
+    /// Reports the failure of an earlier upload, which this one retries
+    async fn flush(&mut self) -> std::io::Result<()> {
+        let error = {
+            let mut staged = self.staged.lock().await;
+            let error = staged.take_error();
+            self.client.staging.save(&staged).await?;
+            error
+        };
+        spawn_upload(self.client.clone(), self.staged.clone());
+        match error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
 
-        // Need to upload a block
-        if self.buffer.len() + buf.len() >= DISCORD_CONTENT_SIZE {
-            let space = DISCORD_CONTENT_SIZE - &self.buffer.len();
-            self.buffer.extend(&buf[..space]);
+#[async_trait]
+impl AsyncRead for DiscordFileWrite {
+    async fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut staged = self.staged.lock().await;
+        let read = staged
+            .read_at(self.client.as_ref(), self.position, buf)
+            .await?;
+        self.position += read as u64;
+        self.size = staged.size();
+        Ok(read)
+    }
+}
 
-            // Upload
-            let message_id = self.upload_buffer().await?;
-            self.prev_id = Some(message_id);
+#[async_trait]
+impl AsyncSeek for DiscordFileWrite {
+    async fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.size = self.staged.lock().await.size();
+        self.position = seek_position(self.position, self.size, pos)?;
+        Ok(self.position)
+    }
+}
 
-            self.buffer.clear();
+/// File that hasn't finished uploading, read from its staging file
+pub struct DiscordStagedRead {
+    id: i64,
+    staged: SharedStaged,
+    position: u64,
+    open_time: SystemTime,
+    total_size: u64,
+    client: Arc<DiscordClientInner>,
+}
 
-            // Store rest of write to buffer
-            self.buffer.extend(&buf[space..])
-        } else {
-            self.buffer.extend(buf);
+impl DiscordStagedRead {
+    pub fn new(client: Arc<DiscordClientInner>, id: i64, staged: SharedStaged) -> Self {
+        Self {
+            id,
+            staged,
+            position: 0,
+            open_time: SystemTime::now(),
+            total_size: 0,
+            client,
         }
-        Ok(buf.len())
     }
+}
 
-    async fn flush(&mut self) -> std::io::Result<()> {
-        if self.buffer.len() > 0 {
-            self.prev_id = Some(self.upload_buffer().await?);
+impl Drop for DiscordStagedRead {
+    fn drop(&mut self) {
+        if let Ok(rt) = Handle::try_current() {
+            let client = self.client.clone();
+            let id = self.id;
+            rt.spawn(async move { client.staging.cleanup(id).await });
         }
-        if let Some(id) = self.prev_id.as_ref() {
-            self.client
-                .db
-                .set_node_cloud_id(&self.node.id, id, self.total_size)
-                .await?;
-        }
-        Ok(())
+    }
+}
+
+impl CloudRead for DiscordStagedRead {
+    fn finish(&self) {
+        let time = self.open_time.elapsed().unwrap_or_default().as_secs_f64();
+        info!(
+            "read {} staged bytes in {}s ({} MiB/s)",
+            self.total_size,
+            time,
+            self.total_size as f64 / (1024.0 * 1024.0 * time)
+        );
+    }
+}
+
+#[async_trait]
+impl AsyncRead for DiscordStagedRead {
+    async fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self
+            .staged
+            .lock()
+            .await
+            .read_at(self.client.as_ref(), self.position, buf)
+            .await?;
+        self.position += read as u64;
+        self.total_size += read as u64;
+        Ok(read)
+    }
+}
+
+#[async_trait]
+impl AsyncSeek for DiscordStagedRead {
+    async fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let size = self.staged.lock().await.size();
+        self.position = seek_position(self.position, size, pos)?;
+        Ok(self.position)
     }
 }
 
 pub struct DiscordFileRead {
-    buffer: Vec<u8>,
-    client: Arc<DiscordClientInner>,
-    file_ids: Vec<u64>,
-    current_index: usize,
+    /// Decrypted contents of the chunk at `chunk_index`
+    chunk: Vec<u8>,
+    chunk_index: Option<usize>,
+    read_ahead: ReadAhead,
+    chunks: Vec<FsChunk>,
+    position: u64,
+    size: u64,
     open_time: SystemTime,
     total_size: u64,
 }
 
 impl DiscordFileRead {
     pub async fn new(client: Arc<DiscordClientInner>, node: FsNode) -> Result<Self, FsError> {
-        let cloud_id = node.cloud_id.as_ref().ok_or_else(|| {
-            FsError::DatabaseError(crate::local::error::DbError::Other(
-                "Cloud id not set".to_string(),
-            ))
-        })?;
-        let ids: Vec<u64> = client
-            .net
-            .get_file_chain(&client.net.channel_id, &cloud_id)
-            .await
-            .map_err(|e| FsError::ClientError(e))?;
-        debug!("file ids: {:?}", ids);
+        let chunks = client.get_chunks(&node).await?;
+        debug!("file chunks: {:?}", chunks);
+        let read_ahead = ReadAhead::new(
+            client.clone(),
+            client.read_ahead,
+            client.read_ahead_permits.clone(),
+        );
         Ok(Self {
-            client,
-            file_ids: ids,
-            buffer: Vec::with_capacity(DISCORD_CONTENT_SIZE),
-            current_index: 0,
+            read_ahead,
+            chunks,
+            chunk: Vec::with_capacity(DISCORD_CONTENT_SIZE),
+            chunk_index: None,
+            position: 0,
+            size: node.size.unwrap_or(0) as u64,
             open_time: SystemTime::now(),
             total_size: 0,
         })
     }
+
+    /// Downloads and decrypts a chunk into the chunk buffer unless it is already there,
+    /// fetching the chunks after it in the background
+    async fn load_chunk(&mut self, index: usize) -> std::io::Result<()> {
+        if self.chunk_index == Some(index) {
+            return Ok(());
+        }
+        if index >= self.chunks.len() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!("chunk {} missing from file", index),
+            ));
+        }
+        self.chunk = self.read_ahead.load(index, &self.chunks).await?;
+        self.chunk_index = Some(index);
+        Ok(())
+    }
 }
 
 impl CloudRead for DiscordFileRead {
-    fn finish(&self) -> () {
+    fn finish(&self) {
         let time = self.open_time.elapsed().unwrap_or_default().as_secs_f64();
         info!(
             "read {} bytes in {}s ({} MiB/s)",
@@ -154,62 +291,48 @@ impl CloudRead for DiscordFileRead {
 impl AsyncRead for DiscordFileRead {
     async fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
         let read_size = buf.len();
-        self.total_size += read_size as u64;
         let mut copied: usize = 0;
-        trace!("read buffer size: {:?}", read_size);
-
-        // Clear buffer first
-        if !self.buffer.is_empty() {
-            let buf_size = self.buffer.len();
-            let copy_size = min(buf_size, read_size);
-            trace!("clearing read buffer: {:?}/{:?} bytes", copy_size, buf_size);
-            buf[..copy_size].clone_from_slice(&self.buffer[..copy_size]);
-            copied += copy_size;
+        trace!("read {:?} bytes at {:?}", read_size, self.position);
 
-            // Left-over buffer
-            if buf_size > copy_size {
-                let leftover = self.buffer[copy_size..].to_vec();
-                self.buffer.clear();
-                self.buffer.extend(&leftover);
-            }
-            // Cleared buffer
-            else if buf_size == copy_size {
-                self.buffer.clear();
-            }
-        }
-
-        // Or need to keep reading
-        while read_size - copied > 0 && self.current_index < self.file_ids.len() {
-            // Fill buffer with next chunk
-            let next_id: String = self
-                .file_ids
-                .get(self.current_index)
-                .unwrap_or(&0)
-                .to_string();
-            debug!("downloading id: {:?}", next_id);
-            let mut download_buffer: Vec<u8> = Vec::with_capacity(DISCORD_BLOCK_SIZE);
-            self.client
-                .net
-                .download_file(&self.client.net.channel_id, &next_id, &mut download_buffer)
-                .await?;
-
-            // Decrypt
-            let decryped_buffer = self.client.aes.decrypt(&mut download_buffer)?;
-            // Copy to output buffer
-            let buf_size = decryped_buffer.len();
-            let copy_size = min(buf_size, read_size - copied);
-            buf[copied..copied + copy_size].clone_from_slice(&decryped_buffer[..copy_size]);
-
-            // Store left-over buffer
-            if buf_size > copy_size {
-                self.buffer.clear();
-                self.buffer.extend(&decryped_buffer[copy_size..]);
+        // Every chunk except the last holds exactly DISCORD_CONTENT_SIZE bytes
+        while copied < read_size && self.position < self.size {
+            let index = (self.position / DISCORD_CONTENT_SIZE as u64) as usize;
+            let chunk_offset = (self.position % DISCORD_CONTENT_SIZE as u64) as usize;
+            self.load_chunk(index).await?;
+            if chunk_offset >= self.chunk.len() {
+                break;
             }
 
+            let copy_size = min(self.chunk.len() - chunk_offset, read_size - copied);
+            buf[copied..copied + copy_size]
+                .copy_from_slice(&self.chunk[chunk_offset..chunk_offset + copy_size]);
             copied += copy_size;
-            self.current_index += 1;
+            self.position += copy_size as u64;
         }
 
+        self.total_size += copied as u64;
         Ok(copied)
     }
 }
+
+#[async_trait]
+impl AsyncSeek for DiscordFileRead {
+    async fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.position = seek_position(self.position, self.size, pos)?;
+        Ok(self.position)
+    }
+}
+
+fn seek_position(position: u64, size: u64, pos: SeekFrom) -> std::io::Result<u64> {
+    let position = match pos {
+        SeekFrom::Start(offset) => Some(offset),
+        SeekFrom::End(offset) => size.checked_add_signed(offset),
+        SeekFrom::Current(offset) => position.checked_add_signed(offset),
+    };
+    position.ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "invalid seek to a negative or overflowing position",
+        )
+    })
+}
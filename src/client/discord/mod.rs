@@ -1,3 +1,8 @@
+pub mod cache;
 pub mod client;
 pub mod file;
 pub mod net;
+pub mod ratelimit;
+pub mod readahead;
+pub mod retry;
+pub mod staging;
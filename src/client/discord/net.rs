@@ -1,18 +1,24 @@
-use std::{cmp::min, env};
+use std::env;
 
 use log::{debug, error, trace};
-use reqwest::{header, multipart, ClientBuilder, StatusCode};
+use reqwest::{header, multipart, ClientBuilder, Response, StatusCode};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use tokio::runtime::Handle;
 
-use crate::{client::error::ClientError, main};
+use crate::client::error::ClientError;
+
+use super::{
+    ratelimit::{RateLimiter, Route},
+    retry::{upload_nonce, RetryPolicy},
+};
 
 const DISCORD_FILENAME: &str = "file.bin";
 
 #[derive(Debug, Deserialize)]
 struct DiscordMessageUpload {
     id: String,
+    attachments: Vec<DiscordAttachment>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -31,6 +37,26 @@ struct DiscordReference {
 #[derive(Debug, Deserialize, Serialize)]
 struct DiscordAttachment {
     id: String,
+    size: u64,
+}
+
+/// Location of one uploaded chunk
+#[derive(Debug, Clone)]
+pub struct DiscordChunk {
+    pub message_id: String,
+    pub attachment_id: String,
+    /// Uploaded size of the attachment
+    pub size: u64,
+}
+
+impl DiscordChunk {
+    fn from_message(message_id: &str, attachment: &DiscordAttachment) -> Self {
+        Self {
+            message_id: message_id.to_owned(),
+            attachment_id: attachment.id.clone(),
+            size: attachment.size,
+        }
+    }
 }
 
 pub struct DiscordNetClient {
@@ -39,10 +65,12 @@ pub struct DiscordNetClient {
     files_url: String,
     pub channel_id: String,
     pub rt: Handle,
+    limiter: RateLimiter,
+    retry: RetryPolicy,
 }
 
 impl DiscordNetClient {
-    pub fn new(rt: Handle) -> Result<Self, ClientError> {
+    pub fn new(rt: Handle, retry: RetryPolicy) -> Result<Self, ClientError> {
         // Set up discord bot token
         let mut default_headers = header::HeaderMap::new();
         let discord_token =
@@ -70,90 +98,130 @@ impl DiscordNetClient {
             channel_id: env::var("CHANNEL_ID")
                 .map_err(|e| ClientError::Initialization(e.to_string()))?,
             rt,
+            limiter: RateLimiter::new(),
+            retry,
         });
     }
 
-    /// Send a message to specified channel and if part of a larger file, link the previous chunk as a reply.
-    /// Returns the id of the created message for future reference
+    /// Send a message with the file attached to specified channel.
+    /// Returns the ids of the created message and its attachment for future reference.
+    /// Retries keep the same nonce, so an attempt that was created but never answered isn't created twice
     pub async fn create_message(
         &self,
         channel_id: &str,
         file: &[u8],
-        reply_id: &Option<String>,
-    ) -> Result<String, ClientError> {
-        let mut form_data = multipart::Form::new();
-
-        let part = multipart::Part::bytes(file.to_owned()).file_name(DISCORD_FILENAME);
-        form_data = form_data.part("files[0]", part);
+    ) -> Result<DiscordChunk, ClientError> {
+        let nonce = upload_nonce();
+        self.retry
+            .run("create message", || {
+                self.try_create_message(channel_id, file, &nonce)
+            })
+            .await
+    }
 
-        if let Some(id) = reply_id {
-            form_data = form_data.text(
-                "payload_json",
-                json!({ "message_reference": {"message_id": id} }).to_string(),
-            );
-        }
+    async fn try_create_message(
+        &self,
+        channel_id: &str,
+        file: &[u8],
+        nonce: &str,
+    ) -> Result<DiscordChunk, ClientError> {
+        let url = format!("{}/channels/{}/messages", &self.url, channel_id);
+        let route = Route::new("POST /channels/{channel}/messages", channel_id);
+        let payload = json!({ "nonce": nonce, "enforce_nonce": true });
+        // The form is built again for every attempt since it can only be sent once
+        let build = || {
+            let mut form_data = multipart::Form::new();
 
-        let builder = self
-            .client
-            .post(format!("{}/channels/{}/messages", &self.url, channel_id))
-            .multipart(form_data)
-            .build()?;
+            let part = multipart::Part::bytes(file.to_owned()).file_name(DISCORD_FILENAME);
+            form_data = form_data.part("files[0]", part);
+            form_data = form_data.text("payload_json", payload.to_string());
 
-        debug!("create message request: {:?}", &builder);
+            debug!("create message request: {}", url);
+            self.client.post(&url).multipart(form_data)
+        };
 
-        let request = self.client.execute(builder).await?;
+        let request = self.limiter.execute(&route, build).await?;
 
         debug!("create message response headers: {:?}", &request);
-        let status = request.status();
-        if status != StatusCode::OK {
-            let body = request.json::<serde_json::Value>().await?;
-            error!(
-                "create message error: {}",
-                serde_json::to_string_pretty(&body).unwrap_or(body.to_string())
-            );
-            return Err(ClientError::RequestValue(format!(
-                "status: {:?}\nbody: {:?}",
-                status, body
-            )));
+        if request.status() != StatusCode::OK {
+            return Err(status_error("create message", request).await);
         }
         let body = request.json::<DiscordMessageUpload>().await?;
         debug!("uploaded message: {}", body.id);
+        let attachment = body.attachments.first().ok_or_else(|| {
+            ClientError::RequestValue(format!("message {} has no attachment", body.id))
+        })?;
+
+        Ok(DiscordChunk::from_message(&body.id, attachment))
+    }
 
-        Ok(body.id)
+    /// Deletes a message along with its attachment
+    pub async fn delete_message(
+        &self,
+        channel_id: &str,
+        message_id: &str,
+    ) -> Result<(), ClientError> {
+        let url = format!(
+            "{}/channels/{}/messages/{}",
+            self.url, channel_id, message_id
+        );
+        let route = Route::new("DELETE /channels/{channel}/messages/{message}", channel_id);
+        self.retry
+            .run("delete message", || async {
+                let response = self
+                    .limiter
+                    .execute(&route, || self.client.delete(&url))
+                    .await?;
+                let status = response.status();
+                // Already deleted is as good as deleted, which also covers a retry after a lost response
+                if status != StatusCode::NO_CONTENT && status != StatusCode::NOT_FOUND {
+                    return Err(status_error("delete message", response).await);
+                }
+                Ok(())
+            })
+            .await?;
+        debug!("deleted message: {}", message_id);
+        Ok(())
     }
 
+    /// Walks the reply chain ending at a message and returns its chunks in upload order
     pub async fn get_file_chain(
         &self,
         channel_id: &str,
         end_id: &str,
-    ) -> Result<Vec<u64>, ClientError> {
-        let mut reverse_ids = vec![];
+    ) -> Result<Vec<DiscordChunk>, ClientError> {
+        let mut reverse_chunks = vec![];
 
+        let route = Route::new("GET /channels/{channel}/messages/{message}", channel_id);
         let mut send_id = Some(end_id.to_owned());
         while let Some(id) = &send_id {
-            let builder = self
-                .client
-                .get(format!(
-                    "{}/channels/{}/messages/{}",
-                    self.url, channel_id, id
-                ))
-                .build()?;
-            debug!("download request: {:?}", builder);
-            let response = self.client.execute(builder).await?;
-
-            let body: DiscordMessageDownload = response.json().await?;
+            let url = format!("{}/channels/{}/messages/{}", self.url, channel_id, id);
+            debug!("download request: {}", url);
+            let body: DiscordMessageDownload = self
+                .retry
+                .run("get message", || async {
+                    let response = self
+                        .limiter
+                        .execute(&route, || self.client.get(&url))
+                        .await?;
+                    if response.status() != StatusCode::OK {
+                        return Err(status_error("get message", response).await);
+                    }
+                    Ok(response.json().await?)
+                })
+                .await?;
             trace!(
                 "download body: {}",
                 serde_json::to_string_pretty(&body).unwrap()
             );
 
             // Can add ids 2 at a time due to message_reference being included
-            if let Some(attachment) = &body.attachments.get(0) {
-                reverse_ids.push(u64::from_str_radix(&attachment.id, 10)?);
+            if let Some(attachment) = body.attachments.first() {
+                reverse_chunks.push(DiscordChunk::from_message(&body.id, attachment));
             }
             if let Some(message) = body.referenced_message {
-                if let Some(attachment) = message.attachments.get(0) {
-                    reverse_ids.push(u64::from_str_radix(&attachment.id, 10)?);
+                if let Some(attachment) = message.attachments.first() {
+                    reverse_chunks.push(DiscordChunk::from_message(&message.id, attachment));
                 }
 
                 // Set next query
@@ -163,7 +231,7 @@ impl DiscordNetClient {
             }
         }
 
-        Ok(reverse_ids.into_iter().rev().collect())
+        Ok(reverse_chunks.into_iter().rev().collect())
     }
 
     /// Download discord attachment.
@@ -174,21 +242,39 @@ impl DiscordNetClient {
         attachment_id: &str,
         buffer: &'a mut Vec<u8>,
     ) -> Result<&'a [u8], ClientError> {
-        let response = self
-            .client
-            .get(format!(
-                "{}/{}/{}/{}",
-                self.files_url, channel_id, attachment_id, DISCORD_FILENAME
-            ))
-            .send()
+        let url = format!(
+            "{}/{}/{}/{}",
+            self.files_url, channel_id, attachment_id, DISCORD_FILENAME
+        );
+        let route = Route::new("GET /attachments/{channel}", channel_id);
+        let body = self
+            .retry
+            .run("download", || async {
+                let response = self
+                    .limiter
+                    .execute(&route, || self.client.get(&url))
+                    .await?;
+                if response.status() != StatusCode::OK {
+                    return Err(status_error("download", response).await);
+                }
+                // A connection dropped halfway through fails here and gets the whole file again
+                Ok(response.bytes().await?)
+            })
             .await?;
-        let body = response.bytes().await?;
         buffer.clear();
         buffer.extend(&body[..body.len()]);
         Ok(&buffer[..body.len()])
     }
 }
 
+/// Error for a response with an unexpected status, keeping its body for the log
+async fn status_error(what: &str, response: Response) -> ClientError {
+    let status = response.status();
+    let body = response.text().await.unwrap_or_default();
+    error!("{} error: {} {}", what, status, body);
+    ClientError::Status(status, body)
+}
+
 #[cfg(test)]
 mod test {
     use std::error::Error;
@@ -203,9 +289,9 @@ mod test {
     #[tokio::test]
     async fn test_create_message() -> TestResult {
         init();
-        let client = DiscordNetClient::new(Handle::current())?;
+        let client = DiscordNetClient::new(Handle::current(), RetryPolicy::new(3))?;
         let _result = client
-            .create_message(&env::var("CHANNEL_ID")?, &vec![0; 6], &None)
+            .create_message(&env::var("CHANNEL_ID")?, &vec![0; 6])
             .await;
         Ok(())
     }
@@ -213,7 +299,7 @@ mod test {
     #[tokio::test]
     async fn test_get_chain() -> TestResult {
         init();
-        let client = DiscordNetClient::new(Handle::current())?;
+        let client = DiscordNetClient::new(Handle::current(), RetryPolicy::new(3))?;
         let result = client
             .get_file_chain(&env::var("CHANNEL_ID")?, "1180822826584912006")
             .await;
@@ -224,7 +310,7 @@ mod test {
     #[tokio::test]
     async fn test_download() -> TestResult {
         init();
-        let client = DiscordNetClient::new(Handle::current())?;
+        let client = DiscordNetClient::new(Handle::current(), RetryPolicy::new(3))?;
         let mut buffer: Vec<u8> = vec![];
         let _result = client
             .download_file(&env::var("CHANNEL_ID")?, "1180822826329055292", &mut buffer)
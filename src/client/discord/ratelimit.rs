@@ -0,0 +1,364 @@
+use std::{
+    cmp::min,
+    collections::HashMap,
+    sync::{Arc, Mutex as SyncMutex},
+    time::{Duration, Instant},
+};
+
+use log::{debug, warn};
+use reqwest::{header::HeaderMap, RequestBuilder, Response, StatusCode};
+use tokio::sync::Mutex;
+
+use crate::client::error::ClientError;
+
+/// How often a request rate limited without a known reset checks again
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// Wait after a 429 that doesn't say how long to wait
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(1);
+/// Rate limited responses in a row before a request gives up
+const MAX_RATE_LIMITED: usize = 10;
+
+/// Endpoint a request goes to, limited separately per channel
+#[derive(Debug, Clone)]
+pub struct Route {
+    pub name: &'static str,
+    /// Channel the request is about, its major parameter
+    pub major: String,
+}
+
+impl Route {
+    pub fn new(name: &'static str, major: &str) -> Self {
+        Self {
+            name,
+            major: major.to_owned(),
+        }
+    }
+}
+
+/// Rate limit headers of a response
+#[derive(Debug, Default, PartialEq)]
+pub struct RateLimitHeaders {
+    pub limit: Option<u64>,
+    pub remaining: Option<u64>,
+    pub reset_after: Option<Duration>,
+    pub bucket: Option<String>,
+    pub global: bool,
+    pub retry_after: Option<Duration>,
+}
+
+impl RateLimitHeaders {
+    pub fn parse(headers: &HeaderMap) -> Self {
+        let get = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+        let seconds = |name: &str| {
+            get(name)
+                .and_then(|value| value.parse::<f64>().ok())
+                .filter(|seconds| seconds.is_finite() && *seconds >= 0.0)
+                .map(Duration::from_secs_f64)
+        };
+        Self {
+            limit: get("x-ratelimit-limit").and_then(|value| value.parse().ok()),
+            remaining: get("x-ratelimit-remaining").and_then(|value| value.parse().ok()),
+            reset_after: seconds("x-ratelimit-reset-after"),
+            bucket: get("x-ratelimit-bucket").map(str::to_owned),
+            global: get("x-ratelimit-global") == Some("true")
+                || get("x-ratelimit-scope") == Some("global"),
+            retry_after: seconds("retry-after"),
+        }
+    }
+}
+
+/// What is known about the requests a bucket has left
+#[derive(Debug, Default)]
+struct BucketState {
+    limit: Option<u64>,
+    /// Requests left until `reset`, counting ones sent but not answered yet
+    remaining: Option<u64>,
+    reset: Option<Instant>,
+}
+
+impl BucketState {
+    /// Takes a request from the bucket, or says how long to wait before asking again
+    fn reserve(&mut self, now: Instant) -> Option<Duration> {
+        if matches!(self.reset, Some(reset) if now >= reset) {
+            self.remaining = self.limit;
+            self.reset = None;
+        }
+        match self.remaining {
+            Some(0) => Some(
+                self.reset
+                    .map_or(POLL_INTERVAL, |reset| reset.saturating_duration_since(now)),
+            ),
+            Some(remaining) => {
+                self.remaining = Some(remaining - 1);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn update(&mut self, now: Instant, status: StatusCode, limits: &RateLimitHeaders) {
+        if limits.limit.is_some() {
+            self.limit = limits.limit;
+        }
+        if let Some(remaining) = limits.remaining {
+            // Within one window the lower count also covers requests still on their way
+            self.remaining = match (self.reset, self.remaining) {
+                (Some(_), Some(current)) => Some(min(current, remaining)),
+                _ => Some(remaining),
+            };
+        }
+        if let Some(reset_after) = limits.reset_after {
+            self.reset = Some(now + reset_after);
+        }
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            self.remaining = Some(0);
+            let retry_after = limits.retry_after.or(limits.reset_after);
+            self.reset = Some(now + retry_after.unwrap_or(DEFAULT_RETRY_AFTER));
+        }
+    }
+}
+
+/// Requests of one bucket queue up in order behind `queue`
+#[derive(Default)]
+struct Bucket {
+    queue: Mutex<()>,
+    state: SyncMutex<BucketState>,
+}
+
+/// Schedules requests around the rate limits Discord reports in its responses
+#[derive(Default)]
+pub struct RateLimiter {
+    /// Bucket hash each route reported, routes with the same hash share their limits
+    routes: SyncMutex<HashMap<&'static str, String>>,
+    buckets: SyncMutex<HashMap<String, Arc<Bucket>>>,
+    global_until: SyncMutex<Option<Instant>>,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn bucket(&self, route: &Route) -> Arc<Bucket> {
+        let key = match self.routes.lock().unwrap().get(route.name) {
+            Some(hash) => format!("{}:{}", hash, route.major),
+            None => format!("{}:{}", route.name, route.major),
+        };
+        self.buckets.lock().unwrap().entry(key).or_default().clone()
+    }
+
+    /// Waits until a request to `route` may be sent
+    async fn acquire(&self, route: &Route) {
+        let bucket = self.bucket(route);
+        let _turn = bucket.queue.lock().await;
+        loop {
+            let global = *self.global_until.lock().unwrap();
+            let now = Instant::now();
+            if let Some(until) = global.filter(|until| *until > now) {
+                debug!("waiting {:?} for the global rate limit", until - now);
+                tokio::time::sleep_until(until.into()).await;
+                continue;
+            }
+            let wait = bucket.state.lock().unwrap().reserve(now);
+            match wait {
+                Some(wait) => {
+                    debug!("waiting {:?} for rate limit of {}", wait, route.name);
+                    tokio::time::sleep(wait).await;
+                }
+                None => return,
+            }
+        }
+    }
+
+    /// Learns the limits of a route from the headers of its response
+    fn update(&self, route: &Route, status: StatusCode, limits: &RateLimitHeaders) {
+        let now = Instant::now();
+        if status == StatusCode::TOO_MANY_REQUESTS && limits.global {
+            let retry_after = limits.retry_after.unwrap_or(DEFAULT_RETRY_AFTER);
+            *self.global_until.lock().unwrap() = Some(now + retry_after);
+            return;
+        }
+        if let Some(hash) = &limits.bucket {
+            self.routes.lock().unwrap().insert(route.name, hash.clone());
+        }
+        self.bucket(route)
+            .state
+            .lock()
+            .unwrap()
+            .update(now, status, limits);
+    }
+
+    /// Sends the request built by `build` once the limits allow it, again after a 429
+    pub async fn execute<F>(&self, route: &Route, build: F) -> Result<Response, ClientError>
+    where
+        F: Fn() -> RequestBuilder,
+    {
+        let mut rate_limited = 0;
+        loop {
+            self.acquire(route).await;
+            let response = build().send().await?;
+            let status = response.status();
+            let limits = RateLimitHeaders::parse(response.headers());
+            self.update(route, status, &limits);
+            if status != StatusCode::TOO_MANY_REQUESTS {
+                return Ok(response);
+            }
+            rate_limited += 1;
+            if rate_limited >= MAX_RATE_LIMITED {
+                return Err(ClientError::RateLimited(route.name.to_owned()));
+            }
+            warn!(
+                "rate limited on {} ({}), retrying after {:?}",
+                route.name,
+                if limits.global { "global" } else { "route" },
+                limits.retry_after.unwrap_or(DEFAULT_RETRY_AFTER)
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use reqwest::header::HeaderValue;
+
+    use super::*;
+
+    fn headers(values: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in values {
+            headers.insert(*name, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    const MESSAGES: &str = "POST /channels/{channel}/messages";
+    const DELETE: &str = "DELETE /channels/{channel}/messages/{message}";
+
+    #[test]
+    fn test_parse_headers() {
+        let limits = RateLimitHeaders::parse(&headers(&[
+            ("x-ratelimit-limit", "5"),
+            ("x-ratelimit-remaining", "4"),
+            ("x-ratelimit-reset-after", "1.25"),
+            ("x-ratelimit-bucket", "abcd"),
+        ]));
+        assert_eq!(
+            limits,
+            RateLimitHeaders {
+                limit: Some(5),
+                remaining: Some(4),
+                reset_after: Some(Duration::from_millis(1250)),
+                bucket: Some("abcd".to_string()),
+                global: false,
+                retry_after: None,
+            }
+        );
+
+        let limits = RateLimitHeaders::parse(&headers(&[
+            ("retry-after", "3"),
+            ("x-ratelimit-global", "true"),
+        ]));
+        assert!(limits.global);
+        assert_eq!(limits.retry_after, Some(Duration::from_secs(3)));
+        assert_eq!(
+            RateLimitHeaders::parse(&HeaderMap::new()),
+            Default::default()
+        );
+    }
+
+    #[test]
+    fn test_bucket_reserve() {
+        let now = Instant::now();
+        let mut bucket = BucketState::default();
+        // Nothing is known until a response says so
+        assert_eq!(bucket.reserve(now), None);
+
+        let limits = RateLimitHeaders {
+            limit: Some(2),
+            remaining: Some(1),
+            reset_after: Some(Duration::from_secs(2)),
+            ..Default::default()
+        };
+        bucket.update(now, StatusCode::OK, &limits);
+        assert_eq!(bucket.reserve(now), None);
+        assert_eq!(bucket.reserve(now), Some(Duration::from_secs(2)));
+
+        // A late response doesn't hand back requests already taken
+        bucket.update(now, StatusCode::OK, &limits);
+        assert_eq!(bucket.remaining, Some(0));
+
+        // The limit comes back once the window resets
+        let later = now + Duration::from_secs(2);
+        assert_eq!(bucket.reserve(later), None);
+        assert_eq!(bucket.reserve(later), None);
+        assert_eq!(bucket.reserve(later), Some(POLL_INTERVAL));
+    }
+
+    #[test]
+    fn test_too_many_requests() {
+        let now = Instant::now();
+        let mut bucket = BucketState::default();
+        let limits = RateLimitHeaders {
+            retry_after: Some(Duration::from_millis(500)),
+            ..Default::default()
+        };
+        bucket.update(now, StatusCode::TOO_MANY_REQUESTS, &limits);
+        assert_eq!(bucket.reserve(now), Some(Duration::from_millis(500)));
+    }
+
+    #[tokio::test]
+    async fn test_limiter_waits() {
+        let limiter = RateLimiter::new();
+        let messages = Route::new(MESSAGES, "1");
+        let other_channel = Route::new(MESSAGES, "2");
+        let limits = RateLimitHeaders {
+            limit: Some(1),
+            remaining: Some(0),
+            reset_after: Some(Duration::from_millis(50)),
+            bucket: Some("hash".to_string()),
+            ..Default::default()
+        };
+        limiter.update(&messages, StatusCode::OK, &limits);
+
+        // Another channel has its own bucket
+        let start = Instant::now();
+        limiter.acquire(&other_channel).await;
+        assert!(start.elapsed() < Duration::from_millis(50));
+        limiter.acquire(&messages).await;
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        // Routes reporting the same bucket share it
+        let delete = Route::new(DELETE, "1");
+        let plenty = RateLimitHeaders {
+            remaining: Some(5),
+            reset_after: Some(Duration::from_secs(1)),
+            bucket: Some("hash".to_string()),
+            ..Default::default()
+        };
+        limiter.update(&delete, StatusCode::OK, &plenty);
+        limiter.update(&messages, StatusCode::OK, &limits);
+        let start = Instant::now();
+        limiter.acquire(&delete).await;
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn test_global_limit() {
+        let limiter = RateLimiter::new();
+        let limits = RateLimitHeaders {
+            global: true,
+            retry_after: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+        limiter.update(
+            &Route::new(MESSAGES, "1"),
+            StatusCode::TOO_MANY_REQUESTS,
+            &limits,
+        );
+
+        // Every route waits out the global limit
+        let start = Instant::now();
+        limiter.acquire(&Route::new(DELETE, "2")).await;
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+}
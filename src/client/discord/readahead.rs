@@ -0,0 +1,185 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use log::{trace, warn};
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    task::JoinHandle,
+};
+
+use crate::{client::error::ClientError, local::db::FsChunk};
+
+use super::staging::ChunkStore;
+
+type Prefetch = JoinHandle<Result<(Vec<u8>, OwnedSemaphorePermit), ClientError>>;
+
+/// Downloads the chunks after the one being read in the background, a permit per chunk
+pub struct ReadAhead {
+    store: Arc<dyn ChunkStore>,
+    /// How many chunks past the current one to fetch
+    depth: usize,
+    permits: Arc<Semaphore>,
+    pending: BTreeMap<usize, Prefetch>,
+}
+
+impl ReadAhead {
+    pub fn new(store: Arc<dyn ChunkStore>, depth: usize, permits: Arc<Semaphore>) -> Self {
+        Self {
+            store,
+            depth,
+            permits,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    /// Content of chunk `index`, taken from its prefetch if there is one
+    pub async fn load(&mut self, index: usize, chunks: &[FsChunk]) -> Result<Vec<u8>, ClientError> {
+        let chunk = &chunks[index];
+        let prefetched = match self.pending.remove(&index) {
+            Some(prefetch) => match prefetch.await {
+                Ok(Ok((data, _permit))) => Some(data),
+                // A failed prefetch gets another try in the foreground
+                Ok(Err(e)) => {
+                    warn!("error prefetching chunk {}: {:?}", index, e);
+                    None
+                }
+                Err(e) => {
+                    warn!("prefetch of chunk {} stopped: {:?}", index, e);
+                    None
+                }
+            },
+            None => None,
+        };
+        self.prefetch(index, chunks);
+        match prefetched {
+            Some(data) => Ok(data),
+            None => self.store.download_chunk(chunk).await,
+        }
+    }
+
+    /// Fetches the chunks after `index` as far as permits allow, dropping ones a seek left behind
+    fn prefetch(&mut self, index: usize, chunks: &[FsChunk]) {
+        let end = chunks.len().min(index + 1 + self.depth);
+        let stale: Vec<usize> = self
+            .pending
+            .keys()
+            .copied()
+            .filter(|i| *i <= index || *i >= end)
+            .collect();
+        for i in stale {
+            if let Some(prefetch) = self.pending.remove(&i) {
+                prefetch.abort();
+            }
+        }
+        for (i, chunk) in chunks.iter().enumerate().take(end).skip(index + 1) {
+            if self.pending.contains_key(&i) {
+                continue;
+            }
+            let Ok(permit) = self.permits.clone().try_acquire_owned() else {
+                trace!("read-ahead limit reached at chunk {}", i);
+                break;
+            };
+            let store = self.store.clone();
+            let chunk = chunk.clone();
+            let prefetch = tokio::spawn(async move {
+                let data = store.download_chunk(&chunk).await?;
+                Ok((data, permit))
+            });
+            self.pending.insert(i, prefetch);
+        }
+    }
+}
+
+impl Drop for ReadAhead {
+    fn drop(&mut self) {
+        // Aborting drops the permits along with the downloads
+        for prefetch in self.pending.values() {
+            prefetch.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{error::Error, sync::atomic::Ordering, time::Duration};
+
+    use crate::util::test::{MemoryStore, TempDir};
+
+    use super::*;
+
+    type TestResult = Result<(), Box<dyn Error>>;
+
+    /// Uploaded chunks whose content is their index
+    fn chunks(store: &MemoryStore, count: usize) -> Vec<FsChunk> {
+        let mut messages = store.messages.lock().unwrap();
+        (0..count)
+            .map(|idx| {
+                messages.insert(format!("a{}", idx), vec![idx as u8]);
+                FsChunk {
+                    node: 2,
+                    idx: idx as i64,
+                    message_id: format!("m{}", idx),
+                    attachment_id: format!("a{}", idx),
+                    size: 1,
+                    cipher_size: 29,
+                }
+            })
+            .collect()
+    }
+
+    /// Waits for the prefetches started so far to finish downloading
+    async fn settle(store: &MemoryStore, downloads: usize) {
+        while store.downloads.load(Ordering::SeqCst) < downloads {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_ahead() -> TestResult {
+        let dir = TempDir::new("readahead");
+        let store = MemoryStore::new(&dir.0).await?;
+        let permits = Arc::new(Semaphore::new(8));
+        let chunks = chunks(&store, 6);
+        let mut reader = ReadAhead::new(store.clone(), 2, permits.clone());
+
+        assert_eq!(reader.load(0, &chunks).await?, [0]);
+        settle(&store, 3).await;
+        assert_eq!(permits.available_permits(), 6);
+
+        // Reading on uses the prefetched chunks and only fetches what is newly in range
+        assert_eq!(reader.load(1, &chunks).await?, [1]);
+        assert_eq!(reader.load(2, &chunks).await?, [2]);
+        settle(&store, 5).await;
+        assert_eq!(store.downloads.load(Ordering::SeqCst), 5);
+
+        // Seeking back drops prefetches that are now out of range
+        assert_eq!(reader.load(0, &chunks).await?, [0]);
+        assert_eq!(reader.pending.keys().copied().collect::<Vec<_>>(), [1, 2]);
+
+        // Dropping the reader gives back every permit
+        drop(reader);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(permits.available_permits(), 8);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_ahead_limit() -> TestResult {
+        let dir = TempDir::new("readahead-limit");
+        let store = MemoryStore::new(&dir.0).await?;
+        let permits = Arc::new(Semaphore::new(1));
+        let chunks = chunks(&store, 6);
+        let mut first = ReadAhead::new(store.clone(), 4, permits.clone());
+        let mut second = ReadAhead::new(store.clone(), 4, permits.clone());
+
+        // Only one chunk may wait in memory across both readers
+        first.load(0, &chunks).await?;
+        second.load(0, &chunks).await?;
+        assert_eq!(first.pending.len(), 1);
+        assert!(second.pending.is_empty());
+
+        // Without prefetches reading still works, one download at a time
+        assert_eq!(second.load(3, &chunks).await?, [3]);
+        assert_eq!(first.load(1, &chunks).await?, [1]);
+        Ok(())
+    }
+}
@@ -0,0 +1,155 @@
+use std::{future::Future, time::Duration};
+
+use log::warn;
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::client::error::ClientError;
+
+/// Delay before the first retry, doubled for every retry after it
+const BASE_DELAY: Duration = Duration::from_millis(500);
+/// Longest delay between two attempts
+const MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// How often and how patiently requests that failed for a passing reason are tried again
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Attempts in total including the first, at least 1
+    pub attempts: usize,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    pub fn new(attempts: usize) -> Self {
+        Self {
+            attempts: attempts.max(1),
+            base_delay: BASE_DELAY,
+            max_delay: MAX_DELAY,
+        }
+    }
+
+    /// Exponential backoff with full jitter
+    pub fn delay(&self, retry: usize) -> Duration {
+        let cap = self
+            .base_delay
+            .saturating_mul(1 << retry.min(16))
+            .min(self.max_delay);
+        let mut random = [0; 8];
+        if SystemRandom::new().fill(&mut random).is_err() {
+            return cap;
+        }
+        cap.mul_f64(u64::from_le_bytes(random) as f64 / u64::MAX as f64)
+    }
+
+    /// Runs `f` until it succeeds, fails for good or runs out of attempts
+    pub async fn run<T, F, Fut>(&self, what: &str, mut f: F) -> Result<T, ClientError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, ClientError>>,
+    {
+        let mut retry = 0;
+        loop {
+            match f().await {
+                Err(e) if e.is_transient() && retry + 1 < self.attempts => {
+                    let delay = self.delay(retry);
+                    warn!(
+                        "{} failed (attempt {}/{}), retrying in {:?}: {}",
+                        what,
+                        retry + 1,
+                        self.attempts,
+                        delay,
+                        e
+                    );
+                    tokio::time::sleep(delay).await;
+                    retry += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Random id that makes retries of an upload return the same message
+pub fn upload_nonce() -> String {
+    let mut random = [0; 8];
+    let _ = SystemRandom::new().fill(&mut random);
+    u64::from_le_bytes(random).to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use reqwest::StatusCode;
+
+    use super::*;
+
+    fn policy(attempts: usize) -> RetryPolicy {
+        RetryPolicy {
+            attempts,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(4),
+        }
+    }
+
+    #[test]
+    fn test_delay() {
+        let policy = RetryPolicy::new(5);
+        for retry in 0..40 {
+            let cap = BASE_DELAY.saturating_mul(1 << retry.min(16)).min(MAX_DELAY);
+            assert!(policy.delay(retry) <= cap);
+        }
+        assert_eq!(RetryPolicy::new(0).attempts, 1);
+        assert_ne!(upload_nonce(), upload_nonce());
+    }
+
+    #[tokio::test]
+    async fn test_retry_transient() {
+        let mut calls = 0;
+        let result = policy(3)
+            .run("test", || {
+                calls += 1;
+                let calls = calls;
+                async move {
+                    match calls {
+                        1 => Err(ClientError::Status(StatusCode::BAD_GATEWAY, String::new())),
+                        _ => Ok(calls),
+                    }
+                }
+            })
+            .await;
+        assert_eq!(result.unwrap(), 2);
+
+        // Giving up after the last attempt returns its error
+        let mut calls = 0;
+        let result: Result<(), _> = policy(3)
+            .run("test", || {
+                calls += 1;
+                async {
+                    Err(ClientError::Status(
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        String::new(),
+                    ))
+                }
+            })
+            .await;
+        assert!(matches!(result, Err(ClientError::Status(..))));
+        assert_eq!(calls, 3);
+    }
+
+    #[tokio::test]
+    async fn test_no_retry_permanent() {
+        let mut calls = 0;
+        let result: Result<(), _> = policy(3)
+            .run("test", || {
+                calls += 1;
+                async { Err(ClientError::Status(StatusCode::FORBIDDEN, String::new())) }
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+
+        let status = |status| ClientError::Status(status, String::new()).is_transient();
+        assert!(status(StatusCode::TOO_MANY_REQUESTS));
+        assert!(!status(StatusCode::REQUEST_TIMEOUT));
+        assert!(!status(StatusCode::NOT_FOUND));
+    }
+}
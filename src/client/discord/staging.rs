@@ -0,0 +1,978 @@
+use std::{
+    cmp::{max, min},
+    collections::{BTreeMap, BTreeSet, HashMap},
+    io::SeekFrom,
+    os::unix::fs::PermissionsExt,
+    path::PathBuf,
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    sync::{Mutex, Semaphore},
+    task::JoinSet,
+};
+
+use crate::{
+    client::error::ClientError,
+    local::{
+        db::{FsChunk, FsDatabase, FsNode},
+        error::FsError,
+    },
+};
+
+pub type SharedStaged = Arc<Mutex<StagedNode>>;
+
+/// Subdirectory of the staging dir that discfs owns, the rest of the dir is left alone
+const STAGING_SUBDIR: &str = "discfs-staging";
+
+/// Where staged chunks are downloaded from and uploaded to
+#[async_trait]
+pub trait ChunkStore: Send + Sync {
+    async fn get_chunks(&self, node: &FsNode) -> Result<Vec<FsChunk>, FsError>;
+    async fn download_chunk(&self, chunk: &FsChunk) -> Result<Vec<u8>, ClientError>;
+    async fn upload_chunk(
+        &self,
+        node: i64,
+        idx: usize,
+        data: &[u8],
+    ) -> Result<FsChunk, ClientError>;
+    /// Deletes uploaded chunks nothing will refer to, logging what can't be deleted
+    async fn discard_chunks(&self, chunks: &[FsChunk]);
+    fn db(&self) -> &FsDatabase;
+    fn staging(&self) -> &Staging;
+}
+
+/// What has to be known about a staged file to finish its upload after a restart
+#[derive(Debug, Serialize, Deserialize)]
+struct StagedState {
+    size: u64,
+    chunks: Vec<Option<FsChunk>>,
+    dirty: Vec<usize>,
+    #[serde(default)]
+    fresh: Vec<FsChunk>,
+    #[serde(default)]
+    superseded: Vec<FsChunk>,
+}
+
+/// File whose changed chunks are kept on local disk until they are uploaded
+pub struct StagedNode {
+    id: i64,
+    file: File,
+    chunk_size: u64,
+    /// Uploaded chunks, `None` where the only copy is in the staging file
+    chunks: Vec<Option<FsChunk>>,
+    /// Chunks in the staging file that still have to be uploaded, with the write that last changed them
+    dirty: BTreeMap<usize, u64>,
+    /// Chunks whose current content is in the staging file, uploaded or not
+    local: BTreeSet<usize>,
+    /// Uploaded since the chunks were last committed, so nothing else knows about them yet
+    fresh: Vec<FsChunk>,
+    /// Uploaded but replaced before being committed, waiting to be deleted
+    superseded: Vec<FsChunk>,
+    /// Counts writes so an upload can tell whether its chunk changed while it was being sent
+    version: u64,
+    size: u64,
+    /// Whether the chunk mapping differs from the database
+    modified: bool,
+    /// Last unmodified chunk downloaded for reading
+    clean: Option<(usize, Vec<u8>)>,
+    /// Held while uploading so only one upload of the node runs at a time
+    upload: Arc<Mutex<()>>,
+    /// Failure of a background upload, reported by the next flush
+    error: Option<std::io::Error>,
+}
+
+impl StagedNode {
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn chunk_len(&self, index: usize) -> u64 {
+        let start = index as u64 * self.chunk_size;
+        min(self.chunk_size, self.size.saturating_sub(start))
+    }
+
+    fn state(&self) -> StagedState {
+        StagedState {
+            size: self.size,
+            chunks: self.chunks.clone(),
+            dirty: self.dirty.keys().copied().collect(),
+            fresh: self.fresh.clone(),
+            superseded: self.superseded.clone(),
+        }
+    }
+
+    /// Queues a replaced chunk for deletion if it was never committed
+    fn supersede(&mut self, chunk: Option<FsChunk>) {
+        let Some(chunk) = chunk else {
+            return;
+        };
+        if let Some(i) = self.fresh.iter().position(|fresh| *fresh == chunk) {
+            self.fresh.swap_remove(i);
+            self.superseded.push(chunk);
+        }
+    }
+
+    /// First chunk waiting to be uploaded that isn't already on its way, with its content
+    async fn next_upload(
+        &mut self,
+        sending: &BTreeSet<usize>,
+    ) -> std::io::Result<Option<(usize, u64, Vec<u8>)>> {
+        let Some((&index, &version)) = self
+            .dirty
+            .iter()
+            .find(|(index, _)| !sending.contains(index))
+        else {
+            return Ok(None);
+        };
+        let mut data = vec![0; self.chunk_len(index) as usize];
+        self.read_staged(index as u64 * self.chunk_size, &mut data)
+            .await?;
+        Ok(Some((index, version, data)))
+    }
+
+    /// Marks a chunk as changed, first copying its current content into the staging file
+    async fn stage(&mut self, store: &dyn ChunkStore, index: usize) -> std::io::Result<()> {
+        self.version += 1;
+        self.modified = true;
+        if self.local.contains(&index) {
+            self.dirty.insert(index, self.version);
+            let replaced = self.chunks[index].take();
+            self.supersede(replaced);
+            return Ok(());
+        }
+        let data = match self.chunks.get(index) {
+            Some(Some(chunk)) => match self.clean.take() {
+                Some((clean_index, data)) if clean_index == index => data,
+                _ => store.download_chunk(chunk).await?,
+            },
+            // Whatever an earlier version left in the staging file gets overwritten
+            _ => vec![0; self.chunk_len(index) as usize],
+        };
+        self.file
+            .seek(SeekFrom::Start(index as u64 * self.chunk_size))
+            .await?;
+        self.file.write_all(&data).await?;
+        if self.chunks.len() <= index {
+            self.chunks.resize(index + 1, None);
+        }
+        let replaced = self.chunks[index].take();
+        self.supersede(replaced);
+        self.dirty.insert(index, self.version);
+        self.local.insert(index);
+        Ok(())
+    }
+
+    /// Reads from the staging file, with zeroes past its end
+    async fn read_staged(&mut self, position: u64, buf: &mut [u8]) -> std::io::Result<()> {
+        self.file.seek(SeekFrom::Start(position)).await?;
+        let mut filled = 0;
+        while filled < buf.len() {
+            let read = self.file.read(&mut buf[filled..]).await?;
+            if read == 0 {
+                break;
+            }
+            filled += read;
+        }
+        buf[filled..].fill(0);
+        Ok(())
+    }
+
+    pub async fn write_at(
+        &mut self,
+        store: &dyn ChunkStore,
+        position: u64,
+        buf: &[u8],
+    ) -> std::io::Result<()> {
+        // Writing past the end leaves a hole of zeroes
+        if position > self.size {
+            self.resize(store, position).await?;
+        }
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let end = position + buf.len() as u64;
+        let first = (position / self.chunk_size) as usize;
+        let last = ((end - 1) / self.chunk_size) as usize;
+        for index in first..=last {
+            self.stage(store, index).await?;
+        }
+        self.file.seek(SeekFrom::Start(position)).await?;
+        self.file.write_all(buf).await?;
+        self.size = max(self.size, end);
+        Ok(())
+    }
+
+    pub async fn read_at(
+        &mut self,
+        store: &dyn ChunkStore,
+        position: u64,
+        buf: &mut [u8],
+    ) -> std::io::Result<usize> {
+        let mut copied = 0;
+        while copied < buf.len() && position + (copied as u64) < self.size {
+            let current = position + copied as u64;
+            let index = (current / self.chunk_size) as usize;
+            let offset = (current % self.chunk_size) as usize;
+            let len = min(buf.len() - copied, self.chunk_len(index) as usize - offset);
+            let dest = &mut buf[copied..copied + len];
+            if self.local.contains(&index) {
+                self.read_staged(current, dest).await?;
+            } else {
+                match self.chunks.get(index) {
+                    Some(Some(chunk)) => {
+                        if !matches!(&self.clean, Some((clean_index, _)) if *clean_index == index) {
+                            let data = store.download_chunk(chunk).await?;
+                            self.clean = Some((index, data));
+                        }
+                        let data = self.clean.as_ref().map(|(_, data)| data.as_slice());
+                        let data = data.unwrap_or_default();
+                        let available = data.len().saturating_sub(offset).min(len);
+                        dest[..available].copy_from_slice(&data[offset..offset + available]);
+                        dest[available..].fill(0);
+                    }
+                    _ => dest.fill(0),
+                }
+            }
+            copied += len;
+        }
+        Ok(copied)
+    }
+
+    /// Grows the file with zeroes or cuts it short, touching only the chunk at the old or new end
+    pub async fn resize(&mut self, store: &dyn ChunkStore, new_size: u64) -> std::io::Result<()> {
+        let chunk_size = self.chunk_size;
+        if new_size == self.size {
+            return Ok(());
+        }
+        let count = new_size.div_ceil(chunk_size) as usize;
+        if new_size < self.size {
+            let cut: Vec<_> = self.chunks.drain(count.min(self.chunks.len())..).collect();
+            for chunk in cut {
+                self.supersede(chunk);
+            }
+            self.dirty.retain(|index, _| *index < count);
+            self.local.retain(|index| *index < count);
+            self.clean = None;
+            if !new_size.is_multiple_of(chunk_size) {
+                self.stage(store, count - 1).await?;
+            }
+            self.file.set_len(new_size).await?;
+        } else {
+            let mut first = (self.size / chunk_size) as usize;
+            // The old last chunk gets filled up with zeroes
+            if !self.size.is_multiple_of(chunk_size) {
+                self.stage(store, first).await?;
+                let end = min(new_size, (first as u64 + 1) * chunk_size);
+                self.file.seek(SeekFrom::Start(self.size)).await?;
+                self.file
+                    .write_all(&vec![0; (end - self.size) as usize])
+                    .await?;
+                first += 1;
+            }
+            // Cut off whatever an earlier version left past the old end
+            self.file.set_len(first as u64 * chunk_size).await?;
+            self.file.set_len(new_size).await?;
+            self.chunks.resize(max(self.chunks.len(), count), None);
+            self.version += 1;
+            for index in first..count {
+                let replaced = self.chunks[index].take();
+                self.supersede(replaced);
+                self.dirty.insert(index, self.version);
+                self.local.insert(index);
+            }
+        }
+        self.size = new_size;
+        self.modified = true;
+        Ok(())
+    }
+
+    /// Takes the failure of the last background upload, if it failed
+    pub fn take_error(&mut self) -> Option<std::io::Error> {
+        self.error.take()
+    }
+}
+
+/// Staged files by node, kept until everything written to them has been uploaded
+pub struct Staging {
+    dir: PathBuf,
+    chunk_size: u64,
+    nodes: Mutex<HashMap<i64, SharedStaged>>,
+    /// One per chunk that may be uploading at a time, across all files
+    upload_permits: Arc<Semaphore>,
+}
+
+impl Staging {
+    pub fn new(dir: PathBuf, chunk_size: u64, parallelism: usize) -> Result<Self, ClientError> {
+        let dir = dir.join(STAGING_SUBDIR);
+        std::fs::create_dir_all(&dir).map_err(|e| {
+            ClientError::Initialization(format!("creating staging dir {:?}: {}", dir, e))
+        })?;
+        // Staged content isn't encrypted, so only the owner gets to see it
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o700)).map_err(|e| {
+            ClientError::Initialization(format!("restricting staging dir {:?}: {}", dir, e))
+        })?;
+        Ok(Self {
+            dir,
+            chunk_size,
+            nodes: Mutex::new(HashMap::new()),
+            upload_permits: Arc::new(Semaphore::new(max(parallelism, 1))),
+        })
+    }
+
+    fn content_path(&self, id: i64) -> PathBuf {
+        self.dir.join(id.to_string())
+    }
+
+    fn state_path(&self, id: i64) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+
+    pub async fn get(&self, id: i64) -> Option<SharedStaged> {
+        self.nodes.lock().await.get(&id).cloned()
+    }
+
+    pub async fn sizes(&self) -> Vec<(i64, u64)> {
+        let staged: Vec<_> = self.nodes.lock().await.values().cloned().collect();
+        let mut sizes = vec![];
+        for staged in staged {
+            let node = staged.lock().await;
+            sizes.push((node.id, node.size()));
+        }
+        sizes
+    }
+
+    /// Staged file of a node, starting one from the uploaded chunks if there isn't one yet
+    pub async fn open(
+        &self,
+        store: &dyn ChunkStore,
+        node: &FsNode,
+        truncate: bool,
+    ) -> std::io::Result<SharedStaged> {
+        let mut nodes = self.nodes.lock().await;
+        if let Some(staged) = nodes.get(&node.id) {
+            let staged = staged.clone();
+            drop(nodes);
+            if truncate {
+                staged.lock().await.resize(store, 0).await?;
+            }
+            return Ok(staged);
+        }
+        let (chunks, size) = if truncate {
+            (vec![], 0)
+        } else {
+            let chunks = store.get_chunks(node).await?;
+            (
+                chunks.into_iter().map(Some).collect(),
+                node.size.unwrap_or(0) as u64,
+            )
+        };
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(self.content_path(node.id))
+            .await?;
+        let staged = Arc::new(Mutex::new(StagedNode {
+            id: node.id,
+            file,
+            chunk_size: self.chunk_size,
+            chunks,
+            dirty: BTreeMap::new(),
+            local: BTreeSet::new(),
+            fresh: vec![],
+            superseded: vec![],
+            version: 0,
+            size,
+            modified: truncate,
+            clean: None,
+            upload: Arc::new(Mutex::new(())),
+            error: None,
+        }));
+        nodes.insert(node.id, staged.clone());
+        Ok(staged)
+    }
+
+    /// Records what is left to upload so it survives a restart
+    pub async fn save(&self, node: &StagedNode) -> std::io::Result<()> {
+        let state = serde_json::to_vec(&node.state())?;
+        let path = self.state_path(node.id);
+        let tmp = path.with_extension("json.tmp");
+        tokio::fs::write(&tmp, state).await?;
+        tokio::fs::rename(&tmp, &path).await
+    }
+
+    /// Drops a staged file once nothing uses it and everything in it is uploaded
+    pub async fn cleanup(&self, id: i64) {
+        let mut nodes = self.nodes.lock().await;
+        let Some(staged) = nodes.get(&id) else {
+            return;
+        };
+        if Arc::strong_count(staged) > 1 {
+            return;
+        }
+        match staged.try_lock() {
+            Ok(node) if !node.modified && node.dirty.is_empty() => {}
+            _ => return,
+        }
+        nodes.remove(&id);
+        drop(nodes);
+        debug!("removing staged file of node {}", id);
+        for path in [self.content_path(id), self.state_path(id)] {
+            if let Err(e) = tokio::fs::remove_file(&path).await {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    warn!("error removing staged file {:?}: {:?}", path, e);
+                }
+            }
+        }
+    }
+
+    /// Picks up uploads left unfinished by an earlier run and removes staged files nobody needs
+    pub async fn resume(store: Arc<dyn ChunkStore>) -> Result<(), FsError> {
+        let staging = store.staging();
+        let mut names = vec![];
+        let mut entries = tokio::fs::read_dir(&staging.dir)
+            .await
+            .map_err(|e| FsError::RuntimeError(e.to_string()))?;
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            names.push(entry.file_name().to_string_lossy().to_string());
+        }
+
+        let mut resumed = vec![];
+        for name in &names {
+            let Some(id) = name
+                .strip_suffix(".json")
+                .and_then(|id| id.parse::<i64>().ok())
+            else {
+                continue;
+            };
+            match staging.resume_node(store.as_ref(), id).await {
+                Ok(Some(staged)) => {
+                    info!("resuming upload of node {}", id);
+                    resumed.push(id);
+                    spawn_upload(store.clone(), staged);
+                }
+                Ok(None) => {}
+                Err(e) => error!("error resuming upload of node {}: {:?}", id, e),
+            }
+        }
+
+        // Content that was never handed off for uploading didn't make it past a flush
+        let open: Vec<i64> = staging.nodes.lock().await.keys().copied().collect();
+        for name in names {
+            let stale = match name.parse::<i64>() {
+                Ok(id) => !resumed.contains(&id) && !open.contains(&id),
+                Err(_) => name.ends_with(".json.tmp"),
+            };
+            if stale {
+                debug!("removing stale staged file {}", name);
+                let _ = tokio::fs::remove_file(staging.dir.join(name)).await;
+            }
+        }
+        Ok(())
+    }
+
+    async fn resume_node(
+        &self,
+        store: &dyn ChunkStore,
+        id: i64,
+    ) -> std::io::Result<Option<SharedStaged>> {
+        let mut nodes = self.nodes.lock().await;
+        if nodes.contains_key(&id) {
+            return Ok(None);
+        }
+        let state: StagedState =
+            serde_json::from_slice(&tokio::fs::read(self.state_path(id)).await?)?;
+        if store.db().get_node_by_id(id as u64).await?.is_none() {
+            info!("dropping staged upload of deleted node {}", id);
+            store.discard_chunks(&state.fresh).await;
+            store.discard_chunks(&state.superseded).await;
+            let _ = tokio::fs::remove_file(self.state_path(id)).await;
+            let _ = tokio::fs::remove_file(self.content_path(id)).await;
+            return Ok(None);
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(self.content_path(id))
+            .await?;
+        let staged = Arc::new(Mutex::new(StagedNode {
+            id,
+            file,
+            chunk_size: self.chunk_size,
+            chunks: state.chunks,
+            dirty: state.dirty.iter().map(|index| (*index, 0)).collect(),
+            local: state.dirty.into_iter().collect(),
+            fresh: state.fresh,
+            superseded: state.superseded,
+            version: 0,
+            size: state.size,
+            modified: true,
+            clean: None,
+            upload: Arc::new(Mutex::new(())),
+            error: None,
+        }));
+        nodes.insert(id, staged.clone());
+        Ok(Some(staged))
+    }
+}
+
+/// Uploads every changed chunk of a staged file and commits the new chunks to the database
+pub async fn upload(store: Arc<dyn ChunkStore>, staged: &SharedStaged) -> std::io::Result<()> {
+    let lock = staged.lock().await.upload.clone();
+    let _uploading = lock.lock().await;
+    let permits = store.staging().upload_permits.clone();
+    let mut running = JoinSet::new();
+    let mut sending = BTreeSet::new();
+    let mut result = Ok(());
+    loop {
+        // No new uploads start once one has failed, the running ones are only waited for
+        while result.is_ok() {
+            // Waiting for a permit only makes sense with nothing of this file left to finish
+            let permit = match permits.clone().try_acquire_owned() {
+                Ok(permit) => permit,
+                Err(_) if running.is_empty() => permits
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .map_err(std::io::Error::other)?,
+                Err(_) => break,
+            };
+            let (id, next) = {
+                let mut node = staged.lock().await;
+                (node.id, node.next_upload(&sending).await)
+            };
+            let (index, version, data) = match next {
+                Ok(Some(next)) => next,
+                Ok(None) => break,
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            };
+            sending.insert(index);
+            let store = store.clone();
+            running.spawn(async move {
+                let _permit = permit;
+                let uploaded = store.upload_chunk(id, index, &data).await;
+                (index, version, uploaded)
+            });
+        }
+        let Some(joined) = running.join_next().await else {
+            break;
+        };
+        let (index, version, uploaded) = joined.map_err(std::io::Error::other)?;
+        sending.remove(&index);
+        match uploaded {
+            Ok(chunk) => {
+                let mut node = staged.lock().await;
+                // Chunks cut off by a truncate are gone from the dirty list too
+                if node.dirty.get(&index) == Some(&version) {
+                    node.dirty.remove(&index);
+                    node.fresh.push(chunk.clone());
+                    node.chunks[index] = Some(chunk);
+                } else {
+                    // Changed or cut off while it was sent, so this copy will never be used
+                    node.superseded.push(chunk);
+                }
+            }
+            Err(e) if result.is_ok() => result = Err(e.into()),
+            Err(e) => warn!("error uploading chunk {}: {:?}", index, e),
+        }
+    }
+
+    let mut node = staged.lock().await;
+    let result = match result {
+        Ok(()) => commit(store.as_ref(), &mut node).await,
+        Err(e) => {
+            // What made it up is remembered, so a retry after a restart can still use or delete it
+            if let Err(save) = store.staging().save(&node).await {
+                warn!("error saving state of node {}: {:?}", node.id, save);
+            }
+            Err(e)
+        }
+    };
+    let superseded = std::mem::take(&mut node.superseded);
+    drop(node);
+    if !superseded.is_empty() {
+        store.discard_chunks(&superseded).await;
+    }
+    result
+}
+
+/// Points the node at its uploaded chunks once none are left to send
+async fn commit(store: &dyn ChunkStore, node: &mut StagedNode) -> std::io::Result<()> {
+    if !node.modified || !node.dirty.is_empty() {
+        return Ok(());
+    }
+    if store.db().get_node_by_id(node.id as u64).await?.is_some() {
+        let chunks: Vec<FsChunk> = node.chunks.iter().flatten().cloned().collect();
+        store
+            .db()
+            .set_node_chunks(node.id, &chunks, node.size as i64)
+            .await?;
+        node.fresh.clear();
+    } else {
+        warn!("node {} was deleted before its upload finished", node.id);
+        let fresh = std::mem::take(&mut node.fresh);
+        node.superseded.extend(fresh);
+    }
+    node.modified = false;
+    if let Err(e) = tokio::fs::remove_file(store.staging().state_path(node.id)).await {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// Uploads in the background, keeping a failure for the next flush to report
+pub fn spawn_upload(store: Arc<dyn ChunkStore>, staged: SharedStaged) {
+    tokio::spawn(async move {
+        let result = upload(store.clone(), &staged).await;
+        let id = {
+            let mut node = staged.lock().await;
+            if let Err(e) = result {
+                error!("error uploading node {}: {:?}", node.id, e);
+                node.error = Some(e);
+            }
+            node.id
+        };
+        drop(staged);
+        store.staging().cleanup(id).await;
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use std::{error::Error, ffi::OsStr, sync::atomic::Ordering, time::Duration};
+
+    use crate::{
+        local::db::NodeOwner,
+        util::test::{MemoryStore, TempDir},
+    };
+
+    use super::*;
+
+    type TestResult = Result<(), Box<dyn Error>>;
+
+    const OWNER: NodeOwner = NodeOwner {
+        mode: 0o644,
+        uid: 1000,
+        gid: 1000,
+    };
+
+    async fn read_all(store: &MemoryStore, staged: &SharedStaged) -> std::io::Result<Vec<u8>> {
+        let mut node = staged.lock().await;
+        let mut buf = vec![0; node.size() as usize];
+        let read = node.read_at(store, 0, &mut buf).await?;
+        assert_eq!(read, buf.len());
+        Ok(buf)
+    }
+
+    #[tokio::test]
+    async fn test_write_and_upload() -> TestResult {
+        let dir = TempDir::new("staging-upload");
+        let store = MemoryStore::new(&dir.0).await?;
+        let file = store
+            .db
+            .create_node(1, OsStr::new("file"), false, OWNER)
+            .await?;
+
+        let staged = store.staging.open(store.as_ref(), &file, false).await?;
+        staged
+            .lock()
+            .await
+            .write_at(store.as_ref(), 2, b"hello world")
+            .await?;
+        assert_eq!(read_all(&store, &staged).await?, b"\0\0hello world");
+        assert!(store.db.get_chunks(file.id).await?.is_empty());
+
+        upload(store.clone(), &staged).await?;
+        let chunks = store.db.get_chunks(file.id).await?;
+        assert_eq!(
+            chunks.iter().map(|c| c.size).collect::<Vec<_>>(),
+            [4, 4, 4, 1]
+        );
+        let node = store.db.get_node_by_id(file.id as u64).await?.unwrap();
+        assert_eq!(node.size, Some(13));
+
+        // Nothing is left to do, so the staged file goes away once unused
+        drop(staged);
+        store.staging.cleanup(file.id).await;
+        assert!(store.staging.get(file.id).await.is_none());
+        assert_eq!(std::fs::read_dir(&store.staging.dir)?.count(), 0);
+
+        // Changing one chunk of the uploaded file uploads only that chunk again
+        let staged = store.staging.open(store.as_ref(), &node, false).await?;
+        staged
+            .lock()
+            .await
+            .write_at(store.as_ref(), 8, b"W")
+            .await?;
+        assert_eq!(read_all(&store, &staged).await?, b"\0\0hello World");
+        upload(store.clone(), &staged).await?;
+        assert_eq!(store.uploads.load(Ordering::SeqCst), 5);
+        let updated = store.db.get_chunks(file.id).await?;
+        assert_eq!(updated[..2], chunks[..2]);
+        assert_ne!(updated[2], chunks[2]);
+        assert_eq!(updated[3], chunks[3]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_append() -> TestResult {
+        let dir = TempDir::new("staging-append");
+        let store = MemoryStore::new(&dir.0).await?;
+        let file = store
+            .db
+            .create_node(1, OsStr::new("file"), false, OWNER)
+            .await?;
+        let staged = store.staging.open(store.as_ref(), &file, false).await?;
+        staged
+            .lock()
+            .await
+            .write_at(store.as_ref(), 0, b"hello")
+            .await?;
+        upload(store.clone(), &staged).await?;
+        drop(staged);
+        store.staging.cleanup(file.id).await;
+        let first = store.db.get_chunks(file.id).await?;
+
+        // Opening with O_APPEND keeps the content and writes where it ends
+        let node = store.db.get_node_by_id(file.id as u64).await?.unwrap();
+        let staged = store.staging.open(store.as_ref(), &node, false).await?;
+        {
+            let mut staged = staged.lock().await;
+            let end = staged.size();
+            staged.write_at(store.as_ref(), end, b" world").await?;
+        }
+        assert_eq!(read_all(&store, &staged).await?, b"hello world");
+        upload(store.clone(), &staged).await?;
+
+        let chunks = store.db.get_chunks(file.id).await?;
+        assert_eq!(chunks.iter().map(|c| c.size).collect::<Vec<_>>(), [4, 4, 3]);
+        // Only the chunk the old content ended in is uploaded again
+        assert_eq!(chunks[0], first[0]);
+        let mut content = vec![];
+        for chunk in &chunks {
+            content.extend(store.download_chunk(chunk).await?);
+        }
+        assert_eq!(content, b"hello world");
+        let node = store.db.get_node_by_id(file.id as u64).await?.unwrap();
+        assert_eq!(node.size, Some(11));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_parallel_upload() -> TestResult {
+        let dir = TempDir::new("staging-parallel");
+        let store = MemoryStore::new(&dir.0).await?;
+        let file = store
+            .db
+            .create_node(1, OsStr::new("file"), false, OWNER)
+            .await?;
+        let staged = store.staging.open(store.as_ref(), &file, false).await?;
+        let content: Vec<u8> = (1..=20).collect();
+        staged
+            .lock()
+            .await
+            .write_at(store.as_ref(), 0, &content)
+            .await?;
+
+        // Chunks go up two at a time, and still end up in order
+        upload(store.clone(), &staged).await?;
+        assert_eq!(store.peak.load(Ordering::SeqCst), 2);
+        let mut uploaded = vec![];
+        for chunk in store.db.get_chunks(file.id).await? {
+            uploaded.extend(store.download_chunk(&chunk).await?);
+        }
+        assert_eq!(uploaded, content);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resize() -> TestResult {
+        let dir = TempDir::new("staging-resize");
+        let store = MemoryStore::new(&dir.0).await?;
+        let file = store
+            .db
+            .create_node(1, OsStr::new("file"), false, OWNER)
+            .await?;
+
+        let staged = store.staging.open(store.as_ref(), &file, false).await?;
+        {
+            let mut node = staged.lock().await;
+            node.write_at(store.as_ref(), 0, b"abcdefghij").await?;
+            node.resize(store.as_ref(), 6).await?;
+            node.resize(store.as_ref(), 14).await?;
+        }
+        assert_eq!(read_all(&store, &staged).await?, b"abcdef\0\0\0\0\0\0\0\0");
+        upload(store.clone(), &staged).await?;
+
+        let chunks = store.db.get_chunks(file.id).await?;
+        assert_eq!(chunks.len(), 4);
+        assert_eq!(chunks[3].size, 2);
+        let mut uploaded = vec![];
+        for chunk in chunks {
+            uploaded.extend(store.download_chunk(&chunk).await?);
+        }
+        assert_eq!(uploaded, b"abcdef\0\0\0\0\0\0\0\0");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_changed_during_upload() -> TestResult {
+        let dir = TempDir::new("staging-changed");
+        let store = MemoryStore::new(&dir.0).await?;
+        let file = store
+            .db
+            .create_node(1, OsStr::new("file"), false, OWNER)
+            .await?;
+        let staged = store.staging.open(store.as_ref(), &file, false).await?;
+        staged
+            .lock()
+            .await
+            .write_at(store.as_ref(), 0, b"old")
+            .await?;
+
+        let gate = store.gate.lock().await;
+        let uploading = {
+            let (store, staged) = (store.clone(), staged.clone());
+            tokio::spawn(async move { upload(store.clone(), &staged).await })
+        };
+        store.started.notified().await;
+        staged
+            .lock()
+            .await
+            .write_at(store.as_ref(), 0, b"new")
+            .await?;
+        drop(gate);
+        uploading.await??;
+
+        // The chunk sent before the write is stale, so it went up again and was deleted
+        assert_eq!(store.uploads.load(Ordering::SeqCst), 2);
+        let chunks = store.db.get_chunks(file.id).await?;
+        assert_eq!(store.download_chunk(&chunks[0]).await?, b"new");
+        assert_eq!(store.messages.lock().unwrap().len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_discard_unused() -> TestResult {
+        let dir = TempDir::new("staging-discard");
+        let store = MemoryStore::new(&dir.0).await?;
+        let file = store
+            .db
+            .create_node(1, OsStr::new("file"), false, OWNER)
+            .await?;
+        let staged = store.staging.open(store.as_ref(), &file, false).await?;
+        {
+            let mut node = staged.lock().await;
+            node.write_at(store.as_ref(), 0, b"aaaabbbbcccc").await?;
+            store.staging.save(&node).await?;
+        }
+
+        // Chunks uploaded but not committed yet, as left by an upload that failed halfway
+        store.fail.store(true, Ordering::SeqCst);
+        assert!(upload(store.clone(), &staged).await.is_err());
+        store.fail.store(false, Ordering::SeqCst);
+        {
+            let mut node = staged.lock().await;
+            for (index, data) in [b"aaaa", b"bbbb", b"cccc"].into_iter().enumerate() {
+                let chunk = store.upload_chunk(file.id, index, data).await?;
+                node.dirty.remove(&index);
+                node.fresh.push(chunk.clone());
+                node.chunks[index] = Some(chunk);
+            }
+            node.write_at(store.as_ref(), 0, b"A").await?;
+            node.resize(store.as_ref(), 6).await?;
+            assert_eq!(node.superseded.len(), 3);
+            assert!(node.fresh.is_empty());
+        }
+
+        // Replaced chunks are deleted and their new content committed
+        upload(store.clone(), &staged).await?;
+        let chunks = store.db.get_chunks(file.id).await?;
+        assert_eq!(store.download_chunk(&chunks[0]).await?, b"Aaaa");
+        assert_eq!(store.download_chunk(&chunks[1]).await?, b"bb");
+        assert_eq!(store.messages.lock().unwrap().len(), 2);
+
+        // Nothing refers to the chunks of a node deleted during its upload
+        staged
+            .lock()
+            .await
+            .write_at(store.as_ref(), 0, b"new")
+            .await?;
+        store.db.delete_node(1, OsStr::new("file"), false).await?;
+        upload(store.clone(), &staged).await?;
+        assert_eq!(store.uploads.load(Ordering::SeqCst), 6);
+        assert_eq!(store.messages.lock().unwrap().len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failure_and_resume() -> TestResult {
+        let dir = TempDir::new("staging-resume");
+        let store = MemoryStore::new(&dir.0).await?;
+        let file = store
+            .db
+            .create_node(1, OsStr::new("file"), false, OWNER)
+            .await?;
+        let deleted = store
+            .db
+            .create_node(1, OsStr::new("gone"), false, OWNER)
+            .await?;
+        let orphaned = store.upload_chunk(deleted.id, 0, b"stag").await?;
+
+        store.fail.store(true, Ordering::SeqCst);
+        for node in [&file, &deleted] {
+            let staged = store.staging.open(store.as_ref(), node, false).await?;
+            let mut staged = staged.lock().await;
+            staged.write_at(store.as_ref(), 0, b"staged").await?;
+            if node.id == deleted.id {
+                staged.fresh.push(orphaned.clone());
+            }
+            store.staging.save(&staged).await?;
+        }
+
+        // A failed background upload is kept for the writer to report
+        let staged = store.staging.get(file.id).await.unwrap();
+        spawn_upload(store.clone(), staged.clone());
+        while staged.lock().await.error.is_none() {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert!(staged.lock().await.take_error().is_some());
+        assert!(store.db.get_chunks(file.id).await?.is_empty());
+        drop(staged);
+
+        // After a restart the upload is picked up again, except for nodes deleted since.
+        // Only files in the discfs subdirectory are swept
+        store.db.delete_node(1, OsStr::new("gone"), false).await?;
+        let store = store.restart();
+        store.fail.store(false, Ordering::SeqCst);
+        std::fs::write(store.staging.dir.join("99"), b"orphan")?;
+        std::fs::write(dir.0.join("98"), b"unrelated")?;
+        Staging::resume(store.clone()).await?;
+        while store.db.get_chunks(file.id).await?.is_empty() {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        let chunks = store.db.get_chunks(file.id).await?;
+        assert_eq!(store.download_chunk(&chunks[1]).await?, b"ed");
+        assert!(!store.staging.dir.join("99").exists());
+        assert!(dir.0.join("98").exists());
+        assert!(!store.staging.state_path(deleted.id).exists());
+        assert!(!store
+            .messages
+            .lock()
+            .unwrap()
+            .contains_key(&orphaned.message_id));
+        Ok(())
+    }
+}
@@ -15,13 +15,50 @@ pub enum ClientError {
     #[error("Request error: {0}")]
     RequestValue(String),
 
+    #[error("Request failed with status {0}: {1}")]
+    Status(reqwest::StatusCode, String),
+
     #[error("Parse error: {0}")]
     Parse(String),
 
+    #[error("Rate limited too many times in a row on {0}")]
+    RateLimited(String),
+
     #[error("Encryption error {0:?}")]
     EncryptionError(#[from] EncryptionError),
 }
 
+impl ClientError {
+    /// Error number to reply to the kernel with. Anything that went wrong talking to the
+    /// cloud is an I/O error for the file
+    pub fn errno(&self) -> libc::c_int {
+        match self {
+            Self::EncryptionError(e) => e.errno(),
+            Self::Initialization(_)
+            | Self::RequestClient(_)
+            | Self::RequestValue(_)
+            | Self::Parse(_)
+            | Self::Status(..)
+            | Self::RateLimited(_) => libc::EIO,
+        }
+    }
+
+    /// Whether the same request may well succeed when sent again,
+    /// like after a timeout, a failed connection, a server error or a rate limit
+    pub fn is_transient(&self) -> bool {
+        let transient = |status: reqwest::StatusCode| {
+            status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+        };
+        match self {
+            Self::RequestClient(e) => {
+                e.is_timeout() || e.is_connect() || e.status().is_some_and(transient)
+            }
+            Self::Status(status, _) => transient(*status),
+            _ => false,
+        }
+    }
+}
+
 impl From<ClientError> for std::io::Error {
     fn from(value: ClientError) -> Self {
         std::io::Error::new(std::io::ErrorKind::Other, value)
@@ -12,6 +12,17 @@ pub enum EncryptionError {
     InvalidKey(String),
 }
 
+impl EncryptionError {
+    /// Error number to reply to the kernel with
+    pub fn errno(&self) -> libc::c_int {
+        match self {
+            // ring only fails to open a sealed chunk when authentication fails
+            Self::AesUnknown => libc::EBADMSG,
+            Self::InvalidKey(_) => libc::EIO,
+        }
+    }
+}
+
 impl From<ring::error::Unspecified> for EncryptionError {
     fn from(_value: ring::error::Unspecified) -> Self {
         Self::AesUnknown
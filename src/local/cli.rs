@@ -1,11 +1,19 @@
-use clap::{ArgAction, Parser};
+use std::path::{Path, PathBuf};
+
+use clap::{ArgAction, Parser, Subcommand};
+
+use crate::client::discord::file::DISCORD_CONTENT_SIZE;
 
 #[derive(Debug, Parser)]
 #[command(name = "discfs")]
 #[command(author = "sqooid")]
 #[command(version = "0.1")]
 #[command(about = "Mounts a virtual filesystem with files stored as Discord file uploads")]
+#[command(subcommand_negates_reqs = true)]
 pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     /// Use dotenv-vault (https://www.dotenv.org/docs/)
     #[arg(long)]
     pub dotenv: bool,
@@ -15,9 +23,148 @@ pub struct Cli {
     pub verbosity: u8,
 
     /// Path to mount virtual filesystem at
-    pub mountpoint: String,
+    #[arg(required = true)]
+    pub mountpoint: Option<String>,
 
     /// Path to create SQLite database file
     #[arg(long, default_value = "./fs.db", env = "DB_PATH")]
     pub db_path: String,
+
+    /// Size of the filesystem reported to df, in bytes or with a K, M, G or T suffix.
+    /// Writes fail with no space left once it is used up
+    #[arg(long, env = "CAPACITY", value_parser = parse_size)]
+    pub capacity: Option<u64>,
+
+    /// Directory writes are kept in until they are uploaded, next to the database by default.
+    /// Uploads left unfinished are resumed from it on the next mount
+    #[arg(long, env = "STAGING_DIR")]
+    pub staging_dir: Option<PathBuf>,
+
+    /// Directory downloaded chunks are cached in, encrypted with the same key as uploads
+    #[arg(long, default_value = "./cache", env = "CACHE_DIR")]
+    pub cache_dir: PathBuf,
+
+    /// Most space the chunk cache takes up, in bytes or with a K, M, G or T suffix.
+    /// The least recently used chunks are dropped to stay under it, 0 turns the cache off
+    #[arg(long, default_value = "1G", env = "CACHE_SIZE", value_parser = parse_size)]
+    pub cache_size: u64,
+
+    /// How many chunks past the one being read get downloaded in the background
+    #[arg(long, default_value_t = 2, env = "READ_AHEAD")]
+    pub read_ahead: usize,
+
+    /// Most memory chunks downloaded ahead of readers take up across all open files,
+    /// in bytes or with a K, M, G or T suffix. Has to fit at least one chunk
+    #[arg(long, default_value = "256M", env = "READ_AHEAD_MEMORY", value_parser = parse_read_ahead_memory)]
+    pub read_ahead_memory: u64,
+
+    /// Most chunks uploading at the same time, across all files
+    #[arg(long, default_value_t = 4, env = "UPLOAD_PARALLELISM")]
+    pub upload_parallelism: usize,
+
+    /// Attempts a request gets when it fails for a passing reason like a timeout or a 5xx,
+    /// waiting a random, exponentially growing time between them
+    #[arg(long, default_value_t = 5, env = "RETRY_ATTEMPTS")]
+    pub retry_attempts: usize,
+}
+
+impl Cli {
+    /// Staging directory, which has to stay the same between mounts wherever discfs runs from
+    pub fn staging_dir(&self) -> PathBuf {
+        match &self.staging_dir {
+            Some(dir) => dir.clone(),
+            None => Path::new(&self.db_path).with_file_name("staging"),
+        }
+    }
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Recursively deletes a path inside the filesystem along with its uploaded messages.
+    /// The filesystem should not be mounted at the same time
+    Rm {
+        /// Path from the root of the filesystem
+        path: PathBuf,
+    },
+}
+
+/// Parses sizes like `512`, `100M` or `2T` using binary units
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let (number, shift) = match value.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => {
+            let shift = match c.to_ascii_uppercase() {
+                'K' => 10,
+                'M' => 20,
+                'G' => 30,
+                'T' => 40,
+                _ => return Err(format!("unknown size suffix: {}", c)),
+            };
+            (&value[..i], shift)
+        }
+        _ => (value, 0),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|e| format!("invalid size {}: {}", value, e))?;
+    number
+        .checked_mul(1 << shift)
+        .ok_or_else(|| format!("size too large: {}", value))
+}
+
+/// Parses a read-ahead memory limit, which has to hold at least one chunk
+pub fn parse_read_ahead_memory(value: &str) -> Result<u64, String> {
+    let size = parse_size(value)?;
+    if size < DISCORD_CONTENT_SIZE as u64 {
+        return Err(format!(
+            "{} is less than one chunk ({} bytes), use --read-ahead 0 to turn read-ahead off",
+            value, DISCORD_CONTENT_SIZE
+        ));
+    }
+    Ok(size)
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("100M"), Ok(100 << 20));
+        assert_eq!(parse_size("2t"), Ok(2 << 40));
+        assert!(parse_size("1X").is_err());
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("99999999999T").is_err());
+    }
+
+    #[test]
+    fn test_read_ahead_memory() {
+        assert_eq!(parse_read_ahead_memory("256M"), Ok(256 << 20));
+        assert!(parse_read_ahead_memory("0").is_err());
+        assert!(parse_read_ahead_memory("1M").is_err());
+        assert!(Cli::try_parse_from(["discfs", "--read-ahead-memory", "1M", "/mnt"]).is_err());
+    }
+
+    #[test]
+    fn test_rm_command() {
+        let cli = Cli::try_parse_from(["discfs", "--db-path", "x.db", "rm", "/a/b"]).unwrap();
+        assert_eq!(cli.db_path, "x.db");
+        assert!(matches!(cli.command, Some(Command::Rm { path }) if path == Path::new("/a/b")));
+        assert!(cli.mountpoint.is_none());
+
+        let cli = Cli::try_parse_from(["discfs", "/mnt"]).unwrap();
+        assert_eq!(cli.mountpoint.as_deref(), Some("/mnt"));
+        assert!(Cli::try_parse_from(["discfs"]).is_err());
+    }
+
+    #[test]
+    fn test_staging_dir() {
+        let cli = Cli::try_parse_from(["discfs", "--db-path", "/data/fs.db", "/mnt"]).unwrap();
+        assert_eq!(cli.staging_dir(), Path::new("/data/staging"));
+        let cli = Cli::try_parse_from(["discfs", "--staging-dir", "/tmp/s", "/mnt"]).unwrap();
+        assert_eq!(cli.staging_dir(), Path::new("/tmp/s"));
+    }
 }
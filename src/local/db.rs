@@ -1,11 +1,31 @@
-use std::{ffi::OsStr, str::FromStr, time::SystemTime};
+use std::{ffi::OsStr, os::unix::ffi::OsStrExt, str::FromStr, time::SystemTime};
 
-use log::info;
-use sqlx::{sqlite::SqliteConnectOptions, Pool, Sqlite, SqlitePool};
+use serde::{Deserialize, Serialize};
+use sqlx::{sqlite::SqliteConnectOptions, Pool, Sqlite, SqlitePool, Transaction};
 
 use crate::util::time::time_to_float;
 
-use super::error::DbError;
+use super::{error::DbError, migrate::migrate};
+
+/// Longest name a directory entry can have, in bytes
+pub const MAX_NAME_LEN: usize = 255;
+
+fn check_name(name: &OsStr) -> Result<(), DbError> {
+    if name.len() > MAX_NAME_LEN {
+        return Err(DbError::NameTooLong(name.to_string_lossy().to_string()));
+    }
+    Ok(())
+}
+
+/// Turns a violation of the unique entry names into `Exists`, for inserts that raced each other
+fn name_taken(error: sqlx::Error, id: i64, name: &OsStr) -> DbError {
+    match &error {
+        sqlx::Error::Database(e) if e.is_unique_violation() => {
+            DbError::Exists(id, name.to_string_lossy().to_string())
+        }
+        _ => error.into(),
+    }
+}
 
 pub struct FsDatabase {
     pub connection: Pool<Sqlite>,
@@ -14,43 +34,23 @@ pub struct FsDatabase {
 impl FsDatabase {
     pub async fn new(path: &str) -> Result<Self, DbError> {
         let db_url = format!("sqlite:{}", path);
-        let connection_options = SqliteConnectOptions::from_str(&db_url)?.create_if_missing(true);
+        // Entries rely on cascading deletes, which sqlite only does with foreign keys on
+        let connection_options = SqliteConnectOptions::from_str(&db_url)?
+            .create_if_missing(true)
+            .foreign_keys(true);
 
         let connection = SqlitePool::connect_with(connection_options).await?;
-
-        {
-            let initialized =
-                sqlx::query!("select name from sqlite_master where type='table' and name='node'")
-                    .fetch_all(&connection)
-                    .await;
-            match initialized {
-                Ok(r) => {
-                    if r.len() == 0 {
-                        Self::initialise_db(&connection).await?;
-                    }
-                }
-                Err(_) => Self::initialise_db(&connection).await?,
-            };
-        }
+        migrate(&connection, path).await?;
 
         return Ok(Self { connection });
     }
 
-    async fn initialise_db(connection: &Pool<Sqlite>) -> Result<(), DbError> {
-        info!("initializing database for the first time");
-        let _ = sqlx::query(include_str!("create_schema.sql"))
-            .execute(connection)
-            .await?;
-
-        Ok(())
-    }
-
     pub async fn get_node(&self, parent: u64, name: &OsStr) -> Result<Option<FsNode>, DbError> {
         let parent_id = parent as i64;
-        let name = name.to_string_lossy();
+        let name = name.as_bytes();
         let node = sqlx::query_as!(
             FsNode,
-            "select * from node where parent=? and name=?",
+            "select id as \"id!\", dirent, name, parent, size, ctime, atime, directory as \"directory!\", cloud_id, mode, uid, gid, mtime, target, nlink as \"nlink!: i64\" from entry where parent=? and name=?",
             parent_id,
             name
         )
@@ -58,9 +58,11 @@ impl FsDatabase {
         .await?;
         Ok(node)
     }
+
+    /// Node with any one of its names, since hard links share the id
     pub async fn get_node_by_id(&self, id: u64) -> Result<Option<FsNode>, DbError> {
         let id = id as i64;
-        let node = sqlx::query_as!(FsNode, "select * from node where id=?", id)
+        let node = sqlx::query_as!(FsNode, "select id as \"id!\", dirent, name, parent, size, ctime, atime, directory as \"directory!\", cloud_id, mode, uid, gid, mtime, target, nlink as \"nlink!: i64\" from entry where id=? limit 1", id)
             .fetch_optional(&self.connection)
             .await?;
         Ok(node)
@@ -71,97 +73,568 @@ impl FsDatabase {
         parent: u64,
         name: &OsStr,
         directory: bool,
+        owner: NodeOwner,
+    ) -> Result<FsNode, DbError> {
+        self.insert_node(parent, name, directory, None, owner).await
+    }
+
+    /// Symlinks store their target in the database and never touch the cloud
+    pub async fn create_symlink(
+        &self,
+        parent: u64,
+        name: &OsStr,
+        target: &[u8],
+        owner: NodeOwner,
+    ) -> Result<FsNode, DbError> {
+        self.insert_node(parent, name, false, Some(target), owner)
+            .await
+    }
+
+    async fn insert_node(
+        &self,
+        parent: u64,
+        name: &OsStr,
+        directory: bool,
+        target: Option<&[u8]>,
+        owner: NodeOwner,
     ) -> Result<FsNode, DbError> {
         let parent_id = parent as i64;
-        let name = name.to_string_lossy();
-        let node = sqlx::query_as!(
-            FsNode,
-            "select * from node where parent=? and name=?",
-            parent_id,
-            name
-        )
-        .fetch_optional(&self.connection)
-        .await?;
-        if let Some(node) = node {
-            return Err(DbError::Exists(node.id, name.to_string()));
-        }
+        check_name(name)?;
+        // Writing first keeps racing transactions from deadlocking on the upgrade to a write lock,
+        // and the unique index turns a taken name into `Exists`
+        let mut transaction = self.connection.begin().await?;
         let ctime = time_to_float(&SystemTime::now()).map_err(|e| DbError::Other(e.to_string()))?;
-        let new_node = sqlx::query_as!(
-            FsNode,
-            "insert into node (parent, name, directory, ctime) values (?, ?, ?, ?); select * from node where parent=? and name=?",
-            parent_id,
-            name,
+        let size = target.map(|t| t.len() as i64);
+        let id = sqlx::query!(
+            "insert into node (directory, target, size, ctime, mtime, atime, mode, uid, gid) values (?, ?, ?, ?, ?, ?, ?, ?, ?)",
             directory,
+            target,
+            size,
             ctime,
-            parent_id,
-            name,
+            ctime,
+            ctime,
+            owner.mode,
+            owner.uid,
+            owner.gid,
         )
-        .fetch_one(&self.connection)
-        .await?;
+        .execute(&mut *transaction)
+        .await?
+        .last_insert_rowid();
+        let new_node = Self::insert_dirent(&mut transaction, id, parent_id, name).await?;
+        transaction.commit().await?;
 
         Ok(new_node)
     }
 
-    pub async fn set_node_cloud_id(
+    /// Adds another name for an existing node
+    pub async fn link_node(
         &self,
-        id: &i64,
-        cloud_id: &str,
+        id: u64,
+        new_parent: u64,
+        new_name: &OsStr,
+    ) -> Result<FsNode, DbError> {
+        let id = id as i64;
+        let parent_id = new_parent as i64;
+        check_name(new_name)?;
+        let mut transaction = self.connection.begin().await?;
+        let node = Self::insert_dirent(&mut transaction, id, parent_id, new_name).await?;
+        transaction.commit().await?;
+        Ok(node)
+    }
+
+    async fn insert_dirent(
+        transaction: &mut Transaction<'_, Sqlite>,
+        id: i64,
+        parent_id: i64,
+        name: &OsStr,
+    ) -> Result<FsNode, DbError> {
+        let bytes = name.as_bytes();
+        sqlx::query!(
+            "insert into dirent (parent, name, node) values (?, ?, ?)",
+            parent_id,
+            bytes,
+            id
+        )
+        .execute(&mut **transaction)
+        .await
+        .map_err(|e| name_taken(e, id, name))?;
+        let node = sqlx::query_as!(
+            FsNode,
+            "select id as \"id!\", dirent, name, parent, size, ctime, atime, directory as \"directory!\", cloud_id, mode, uid, gid, mtime, target, nlink as \"nlink!: i64\" from entry where parent=? and name=?",
+            parent_id,
+            bytes
+        )
+        .fetch_one(&mut **transaction)
+        .await?;
+        Ok(node)
+    }
+
+    /// Replaces the chunks of a node and updates its size in a single transaction.
+    /// The cloud id is kept pointing at the last chunk for older clients
+    pub async fn set_node_chunks(
+        &self,
+        id: i64,
+        chunks: &[FsChunk],
         size: i64,
     ) -> Result<(), DbError> {
+        let mut transaction = self.connection.begin().await?;
+        let cloud_id = chunks.last().map(|c| c.message_id.as_str());
+        let mtime = time_to_float(&SystemTime::now()).map_err(|e| DbError::Other(e.to_string()))?;
         let result = sqlx::query!(
-            "update node set cloud_id=?, size=? where id=?",
+            "update node set cloud_id=?, size=?, mtime=?, sha256=null where id=?",
             cloud_id,
             size,
+            mtime,
+            id,
+        )
+        .execute(&mut *transaction)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Err(DbError::DoesNotExist(id));
+        }
+        sqlx::query!("delete from chunk where node=?", id)
+            .execute(&mut *transaction)
+            .await?;
+        for (idx, chunk) in chunks.iter().enumerate() {
+            let idx = idx as i64;
+            sqlx::query!(
+                "insert into chunk (node, idx, message_id, attachment_id, size, cipher_size) values (?, ?, ?, ?, ?, ?)",
+                id,
+                idx,
+                chunk.message_id,
+                chunk.attachment_id,
+                chunk.size,
+                chunk.cipher_size,
+            )
+            .execute(&mut *transaction)
+            .await?;
+        }
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    /// Records the chunks found by walking the reply chain of a node uploaded before chunks
+    /// were tracked. Nothing is written if the node has been rewritten since it was read,
+    /// either with chunks of its own or under another message.
+    /// Returns whether the chunks were recorded
+    pub async fn set_migrated_chunks(
+        &self,
+        id: i64,
+        cloud_id: &str,
+        chunks: &[FsChunk],
+    ) -> Result<bool, DbError> {
+        let mut transaction = self.connection.begin().await?;
+        // Writing first takes the database lock, so no upload can land between the check and the inserts
+        let result = sqlx::query!(
+            "update node set cloud_id=cloud_id where id=? and cloud_id=? and not exists (select 1 from chunk where chunk.node=node.id)",
+            id,
+            cloud_id,
+        )
+        .execute(&mut *transaction)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+        for (idx, chunk) in chunks.iter().enumerate() {
+            let idx = idx as i64;
+            sqlx::query!(
+                "insert into chunk (node, idx, message_id, attachment_id, size, cipher_size) values (?, ?, ?, ?, ?, ?)",
+                id,
+                idx,
+                chunk.message_id,
+                chunk.attachment_id,
+                chunk.size,
+                chunk.cipher_size,
+            )
+            .execute(&mut *transaction)
+            .await?;
+        }
+        transaction.commit().await?;
+        Ok(true)
+    }
+
+    /// Updates the attributes that are set, leaving the rest as they are
+    pub async fn set_node_attributes(
+        &self,
+        id: i64,
+        attributes: NodeAttributes,
+    ) -> Result<(), DbError> {
+        let to_float = |time: Option<SystemTime>| {
+            time.map(|t| time_to_float(&t))
+                .transpose()
+                .map_err(|e| DbError::Other(e.to_string()))
+        };
+        let atime = to_float(attributes.atime)?;
+        let mtime = to_float(attributes.mtime)?;
+        let result = sqlx::query!(
+            "update node set mode=coalesce(?, mode), uid=coalesce(?, uid), gid=coalesce(?, gid), atime=coalesce(?, atime), mtime=coalesce(?, mtime) where id=?",
+            attributes.mode,
+            attributes.uid,
+            attributes.gid,
+            atime,
+            mtime,
             id,
         )
         .execute(&self.connection)
         .await?;
         if result.rows_affected() == 0 {
-            Err(DbError::DoesNotExist(*id))
+            Err(DbError::DoesNotExist(id))
         } else {
             Ok(())
         }
     }
 
-    pub async fn get_nodes_by_parent(&self, parent_id: i64) -> Result<Vec<FsNode>, DbError> {
-        let result = sqlx::query_as!(FsNode, "select * from node where parent=?", parent_id)
+    pub async fn get_xattr(&self, id: i64, name: &str) -> Result<Option<Vec<u8>>, DbError> {
+        let value =
+            sqlx::query_scalar!("select value from xattr where node=? and name=?", id, name)
+                .fetch_optional(&self.connection)
+                .await?;
+        Ok(value)
+    }
+
+    pub async fn list_xattrs(&self, id: i64) -> Result<Vec<String>, DbError> {
+        let names = sqlx::query_scalar!("select name from xattr where node=? order by name", id)
+            .fetch_all(&self.connection)
+            .await?;
+        Ok(names)
+    }
+
+    /// Sets an extended attribute, failing with `Exists` or `DoesNotExist` when the mode
+    /// requires the attribute to be missing or present
+    pub async fn set_xattr(
+        &self,
+        id: i64,
+        name: &str,
+        value: &[u8],
+        mode: XattrMode,
+    ) -> Result<(), DbError> {
+        let mut transaction = self.connection.begin().await?;
+        let exists = sqlx::query!(
+            "select 1 as found from xattr where node=? and name=?",
+            id,
+            name
+        )
+        .fetch_optional(&mut *transaction)
+        .await?
+        .is_some();
+        match mode {
+            XattrMode::Create if exists => return Err(DbError::Exists(id, name.to_string())),
+            XattrMode::Replace if !exists => return Err(DbError::DoesNotExist(id)),
+            _ => {}
+        }
+        sqlx::query!(
+            "insert or replace into xattr (node, name, value) values (?, ?, ?)",
+            id,
+            name,
+            value
+        )
+        .execute(&mut *transaction)
+        .await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    /// Returns whether the attribute existed
+    pub async fn remove_xattr(&self, id: i64, name: &str) -> Result<bool, DbError> {
+        let result = sqlx::query!("delete from xattr where node=? and name=?", id, name)
+            .execute(&self.connection)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Hash of the content, cleared whenever the chunks change
+    pub async fn get_sha256(&self, id: i64) -> Result<Option<String>, DbError> {
+        let hash = sqlx::query_scalar!("select sha256 from node where id=?", id)
+            .fetch_optional(&self.connection)
+            .await?;
+        Ok(hash.flatten())
+    }
+
+    /// Caches a hash unless the content changed since `mtime` was read
+    pub async fn set_sha256(&self, id: i64, hash: &str, mtime: Option<f64>) -> Result<(), DbError> {
+        sqlx::query!(
+            "update node set sha256=? where id=? and mtime is ?",
+            hash,
+            id,
+            mtime
+        )
+        .execute(&self.connection)
+        .await?;
+        Ok(())
+    }
+
+    /// Space taken by file content and the number of nodes
+    pub async fn get_usage(&self) -> Result<FsUsage, DbError> {
+        let usage = sqlx::query_as!(
+            FsUsage,
+            "select coalesce(sum(size), 0) as \"bytes!: i64\", count(*) as \"nodes!: i64\" from node"
+        )
+        .fetch_one(&self.connection)
+        .await?;
+        Ok(usage)
+    }
+
+    pub async fn get_chunks(&self, id: i64) -> Result<Vec<FsChunk>, DbError> {
+        let chunks = sqlx::query_as!(FsChunk, "select * from chunk where node=? order by idx", id)
+            .fetch_all(&self.connection)
+            .await?;
+        Ok(chunks)
+    }
+
+    /// Nodes uploaded before chunks were tracked, which only know the end of their reply chain
+    pub async fn get_unchunked_nodes(&self) -> Result<Vec<FsNode>, DbError> {
+        let nodes = sqlx::query_as!(
+            FsNode,
+            "select id as \"id!\", dirent, name, parent, size, ctime, atime, directory as \"directory!\", cloud_id, mode, uid, gid, mtime, target, nlink as \"nlink!: i64\" from entry where cloud_id is not null and not exists (select 1 from chunk where chunk.node=entry.id) group by id"
+        )
+        .fetch_all(&self.connection)
+        .await?;
+        Ok(nodes)
+    }
+
+    /// Children in the order their entries were created, starting after the entry `after`
+    pub async fn get_nodes_by_parent(
+        &self,
+        parent_id: i64,
+        after: i64,
+        limit: i64,
+    ) -> Result<Vec<FsNode>, DbError> {
+        let result = sqlx::query_as!(FsNode, "select id as \"id!\", dirent, name, parent, size, ctime, atime, directory as \"directory!\", cloud_id, mode, uid, gid, mtime, target, nlink as \"nlink!: i64\" from entry where parent=? and dirent>? order by dirent limit ?", parent_id, after, limit)
             .fetch_all(&self.connection)
             .await?;
         Ok(result)
     }
 
-    pub async fn delete_node(&self, parent_id: i64, name: &str, dir: bool) -> Result<u64, DbError> {
-        let result = sqlx::query!(
-            "delete from node where parent=? and name=? and directory=?",
+    /// Removes a name, and the node itself once no names are left.
+    /// Directories have to be empty first. Returns `None` if there was no such entry,
+    /// otherwise the chunks of the node if it was dropped so their content can be deleted
+    pub async fn delete_node(
+        &self,
+        parent_id: i64,
+        name: &OsStr,
+        dir: bool,
+    ) -> Result<Option<Vec<FsChunk>>, DbError> {
+        let name = name.as_bytes();
+        let mut transaction = self.connection.begin().await?;
+        let entry = sqlx::query!(
+            "select dirent.id, dirent.node from dirent join node on dirent.node=node.id where parent=? and name=? and directory=?",
             parent_id,
             name,
             dir
         )
-        .execute(&self.connection)
+        .fetch_optional(&mut *transaction)
         .await?;
-        Ok(result.rows_affected())
+        let Some(entry) = entry else {
+            return Ok(None);
+        };
+        if dir {
+            let child = sqlx::query!("select id from dirent where parent=? limit 1", entry.node)
+                .fetch_optional(&mut *transaction)
+                .await?;
+            if child.is_some() {
+                return Err(DbError::NotEmpty(entry.node));
+            }
+        }
+        sqlx::query!("delete from dirent where id=?", entry.id)
+            .execute(&mut *transaction)
+            .await?;
+        let chunks = Self::drop_unnamed(&mut transaction, entry.node).await?;
+        transaction.commit().await?;
+        Ok(Some(chunks))
     }
 
+    /// Deletes a node if no entry names it anymore, returning the chunks it had
+    async fn drop_unnamed(
+        transaction: &mut Transaction<'_, Sqlite>,
+        node: i64,
+    ) -> Result<Vec<FsChunk>, DbError> {
+        let chunks = sqlx::query_as!(
+            FsChunk,
+            "select chunk.* from chunk where node=? and not exists (select 1 from dirent where dirent.node=chunk.node) order by idx",
+            node
+        )
+        .fetch_all(&mut **transaction)
+        .await?;
+        sqlx::query!(
+            "delete from node where id=? and not exists (select 1 from dirent where dirent.node=node.id)",
+            node
+        )
+        .execute(&mut **transaction)
+        .await?;
+        Ok(chunks)
+    }
+
+    /// Chunks of every node that removing a name along with everything below it would leave
+    /// without a name, so their content can be deleted before the entries are
+    pub async fn tree_chunks(&self, parent_id: i64, name: &OsStr) -> Result<Vec<FsChunk>, DbError> {
+        let mut transaction = self.connection.begin().await?;
+        let chunks = Self::remove_tree(&mut transaction, parent_id, name).await?;
+        // Only the chunks were wanted
+        transaction.rollback().await?;
+        Ok(chunks)
+    }
+
+    /// Removes a name along with everything below it.
+    /// Returns the chunks of every node that was left without a name so their content can be deleted
+    pub async fn delete_tree(&self, parent_id: i64, name: &OsStr) -> Result<Vec<FsChunk>, DbError> {
+        let mut transaction = self.connection.begin().await?;
+        let chunks = Self::remove_tree(&mut transaction, parent_id, name).await?;
+        transaction.commit().await?;
+        Ok(chunks)
+    }
+
+    async fn remove_tree(
+        transaction: &mut Transaction<'_, Sqlite>,
+        parent_id: i64,
+        name: &OsStr,
+    ) -> Result<Vec<FsChunk>, DbError> {
+        let name = name.as_bytes();
+        let deleted = sqlx::query!(
+            "delete from dirent where parent=? and name=?",
+            parent_id,
+            name
+        )
+        .execute(&mut **transaction)
+        .await?;
+        if deleted.rows_affected() == 0 {
+            return Err(DbError::DoesNotExist(parent_id));
+        }
+
+        // Deleting a directory cascades to its entries, which can leave more nodes without names
+        let mut chunks = vec![];
+        loop {
+            chunks.extend(
+                sqlx::query_as!(
+                    FsChunk,
+                    "select chunk.* from chunk join node on chunk.node=node.id where node.id!=1 and not exists (select 1 from dirent where dirent.node=node.id)"
+                )
+                .fetch_all(&mut **transaction)
+                .await?,
+            );
+            let orphans = sqlx::query!(
+                "delete from node where id!=1 and not exists (select 1 from dirent where dirent.node=node.id)"
+            )
+            .execute(&mut **transaction)
+            .await?;
+            if orphans.rows_affected() == 0 {
+                break;
+            }
+        }
+        Ok(chunks)
+    }
+
+    /// Renames an entry in one transaction.
+    /// An existing target is replaced, kept or swapped with the source depending on `mode`.
+    /// Returns the chunks of a replaced target that was left without a name
     pub async fn move_node(
         &self,
         parent: i64,
-        name: &str,
+        name: &OsStr,
         new_parent: i64,
-        new_name: &str,
-    ) -> Result<(), DbError> {
-        let result = sqlx::query!(
-            "update node set parent=?, name=? where parent=? and name=?",
-            new_parent,
-            new_name,
+        new_name: &OsStr,
+        mode: RenameMode,
+    ) -> Result<Vec<FsChunk>, DbError> {
+        check_name(new_name)?;
+        let mut transaction = self.connection.begin().await?;
+        let Some(source) = Self::get_entry(&mut transaction, parent, name).await? else {
+            return Err(DbError::DoesNotExist(parent));
+        };
+        let target = Self::get_entry(&mut transaction, new_parent, new_name).await?;
+        if source.directory {
+            Self::check_not_inside(&mut transaction, source.node, new_parent).await?;
+        }
+
+        let mut chunks = vec![];
+        match (mode, target) {
+            (RenameMode::Exchange, None) => return Err(DbError::DoesNotExist(new_parent)),
+            (RenameMode::Exchange, Some(target)) => {
+                if target.directory {
+                    Self::check_not_inside(&mut transaction, target.node, parent).await?;
+                }
+                // Swapping the nodes keeps both entries where they are
+                for (entry, node) in [(source.id, target.node), (target.id, source.node)] {
+                    sqlx::query!("update dirent set node=? where id=?", node, entry)
+                        .execute(&mut *transaction)
+                        .await?;
+                }
+            }
+            (RenameMode::NoReplace, Some(target)) if target.id != source.id => {
+                return Err(DbError::Exists(
+                    target.node,
+                    new_name.to_string_lossy().to_string(),
+                ));
+            }
+            (_, Some(target)) if target.node == source.node => {
+                // Both names are already the same node, which POSIX leaves alone
+            }
+            (_, target) => {
+                if let Some(target) = target {
+                    match (source.directory, target.directory) {
+                        (true, false) => return Err(DbError::NotDirectory(target.node)),
+                        (false, true) => return Err(DbError::IsDirectory(target.node)),
+                        _ => {}
+                    }
+                    let child =
+                        sqlx::query!("select id from dirent where parent=? limit 1", target.node)
+                            .fetch_optional(&mut *transaction)
+                            .await?;
+                    if child.is_some() {
+                        return Err(DbError::NotEmpty(target.node));
+                    }
+                    sqlx::query!("delete from dirent where id=?", target.id)
+                        .execute(&mut *transaction)
+                        .await?;
+                    chunks = Self::drop_unnamed(&mut transaction, target.node).await?;
+                }
+                let bytes = new_name.as_bytes();
+                sqlx::query!(
+                    "update dirent set parent=?, name=? where id=?",
+                    new_parent,
+                    bytes,
+                    source.id
+                )
+                .execute(&mut *transaction)
+                .await
+                .map_err(|e| name_taken(e, source.node, new_name))?;
+            }
+        }
+        transaction.commit().await?;
+        Ok(chunks)
+    }
+
+    async fn get_entry(
+        transaction: &mut Transaction<'_, Sqlite>,
+        parent: i64,
+        name: &OsStr,
+    ) -> Result<Option<DirEntry>, DbError> {
+        let name = name.as_bytes();
+        let entry = sqlx::query_as!(
+            DirEntry,
+            "select dirent.id as \"id!\", dirent.node, node.directory from dirent join node on dirent.node=node.id where parent=? and name=?",
             parent,
             name
         )
-        .execute(&self.connection)
+        .fetch_optional(&mut **transaction)
         .await?;
-        if result.rows_affected() > 0 {
-            Ok(())
-        } else {
-            Err(DbError::DoesNotExist(0))
+        Ok(entry)
+    }
+
+    /// Fails if `dir` is the directory `node` or one of its ancestors
+    async fn check_not_inside(
+        transaction: &mut Transaction<'_, Sqlite>,
+        node: i64,
+        dir: i64,
+    ) -> Result<(), DbError> {
+        let inside = sqlx::query!(
+            "with recursive ancestor(id) as (select ? union select dirent.parent from dirent join ancestor on dirent.node=ancestor.id) select id from ancestor where id=?",
+            dir,
+            node
+        )
+        .fetch_optional(&mut **transaction)
+        .await?;
+        match inside {
+            Some(_) => Err(DbError::MoveIntoSelf(node)),
+            None => Ok(()),
         }
     }
 }
@@ -169,11 +642,669 @@ impl FsDatabase {
 #[derive(Debug, Clone)]
 pub struct FsNode {
     pub id: i64,
-    pub name: Option<String>,
+    /// Directory entry the node was looked up through, `None` for the root
+    pub dirent: Option<i64>,
+    /// Name and parent of the entry the node was looked up through, as raw bytes
+    pub name: Option<Vec<u8>>,
+    pub parent: Option<i64>,
     pub size: Option<i64>,
     pub ctime: Option<f64>,
     pub atime: Option<f64>,
-    pub parent: Option<i64>,
     pub directory: bool,
     pub cloud_id: Option<String>,
+    pub mode: Option<i64>,
+    pub uid: Option<i64>,
+    pub gid: Option<i64>,
+    pub mtime: Option<f64>,
+    /// Set for symlinks only
+    pub target: Option<Vec<u8>>,
+    /// Names of a file, or 2 plus subdirectories for a directory
+    pub nlink: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeKind {
+    File,
+    Directory,
+    Symlink,
+}
+
+impl FsNode {
+    pub fn kind(&self) -> NodeKind {
+        if self.directory {
+            NodeKind::Directory
+        } else if self.target.is_some() {
+            NodeKind::Symlink
+        } else {
+            NodeKind::File
+        }
+    }
+
+    /// Name of the entry the node was looked up through, empty for the root
+    pub fn file_name(&self) -> &OsStr {
+        OsStr::from_bytes(self.name.as_deref().unwrap_or_default())
+    }
+}
+
+/// Permissions and ownership given to a new node
+#[derive(Debug, Clone, Copy)]
+pub struct NodeOwner {
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// Attributes changed by setattr, `None` for ones left alone
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NodeAttributes {
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub atime: Option<SystemTime>,
+    pub mtime: Option<SystemTime>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FsUsage {
+    pub bytes: i64,
+    pub nodes: i64,
+}
+
+/// How `set_xattr` treats an existing attribute, following XATTR_CREATE and XATTR_REPLACE
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum XattrMode {
+    Upsert,
+    Create,
+    Replace,
+}
+
+/// How `move_node` treats an existing target, following RENAME_NOREPLACE and RENAME_EXCHANGE
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RenameMode {
+    Replace,
+    NoReplace,
+    Exchange,
+}
+
+/// Name of a node inside a directory
+struct DirEntry {
+    id: i64,
+    node: i64,
+    directory: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FsChunk {
+    pub node: i64,
+    pub idx: i64,
+    pub message_id: String,
+    pub attachment_id: String,
+    /// Plaintext length
+    pub size: i64,
+    /// Uploaded length including the tag and nonce
+    pub cipher_size: i64,
+}
+
+#[cfg(test)]
+mod test {
+    use std::error::Error;
+
+    use super::*;
+
+    type TestResult = Result<(), Box<dyn Error>>;
+
+    const OWNER: NodeOwner = NodeOwner {
+        mode: 0o644,
+        uid: 1000,
+        gid: 1000,
+    };
+
+    fn chunk(node: i64, idx: i64) -> FsChunk {
+        FsChunk {
+            node,
+            idx,
+            message_id: format!("m{}", idx),
+            attachment_id: format!("a{}", idx),
+            size: 10,
+            cipher_size: 38,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_set_node_chunks() -> TestResult {
+        let db = FsDatabase::new(":memory:").await?;
+        let node = db.create_node(1, OsStr::new("file"), false, OWNER).await?;
+
+        db.set_node_chunks(node.id, &[chunk(node.id, 0), chunk(node.id, 1)], 20)
+            .await?;
+        let chunks = db.get_chunks(node.id).await?;
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[1].attachment_id, "a1");
+
+        // Replacing drops chunks that are no longer part of the file
+        db.set_node_chunks(node.id, &[chunk(node.id, 0)], 10)
+            .await?;
+        assert_eq!(db.get_chunks(node.id).await?.len(), 1);
+        let node = db.get_node_by_id(node.id as u64).await?.unwrap();
+        assert_eq!(node.cloud_id.as_deref(), Some("m0"));
+        assert_eq!(node.size, Some(10));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_set_migrated_chunks() -> TestResult {
+        let db = FsDatabase::new(":memory:").await?;
+        let node = db.create_node(1, OsStr::new("file"), false, OWNER).await?;
+        db.set_node_chunks(node.id, &[chunk(node.id, 0)], 10)
+            .await?;
+        sqlx::query("delete from chunk")
+            .execute(&db.connection)
+            .await?;
+
+        // A walk that raced a rewrite under another message is dropped
+        assert!(
+            !db.set_migrated_chunks(node.id, "old", &[chunk(node.id, 1)])
+                .await?
+        );
+        assert!(db.get_chunks(node.id).await?.is_empty());
+
+        assert!(
+            db.set_migrated_chunks(node.id, "m0", &[chunk(node.id, 0)])
+                .await?
+        );
+        // Once chunks are there a second walk leaves them alone
+        assert!(
+            !db.set_migrated_chunks(node.id, "m0", &[chunk(node.id, 5)])
+                .await?
+        );
+        let chunks = db.get_chunks(node.id).await?;
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].attachment_id, "a0");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_set_node_attributes() -> TestResult {
+        let db = FsDatabase::new(":memory:").await?;
+        let node = db.create_node(1, OsStr::new("file"), false, OWNER).await?;
+
+        let attributes = NodeAttributes {
+            mode: Some(0o600),
+            gid: Some(100),
+            ..Default::default()
+        };
+        db.set_node_attributes(node.id, attributes).await?;
+        let updated = db.get_node_by_id(node.id as u64).await?.unwrap();
+        assert_eq!(updated.mode, Some(0o600));
+        assert_eq!(updated.uid, Some(1000));
+        assert_eq!(updated.gid, Some(100));
+        assert_eq!(updated.mtime, node.mtime);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_create_symlink() -> TestResult {
+        let db = FsDatabase::new(":memory:").await?;
+        let link = db
+            .create_symlink(1, OsStr::new("link"), b"../target", OWNER)
+            .await?;
+        assert_eq!(link.kind(), NodeKind::Symlink);
+        assert_eq!(link.target.as_deref(), Some(&b"../target"[..]));
+        assert_eq!(link.size, Some(9));
+
+        let file = db.create_node(1, OsStr::new("file"), false, OWNER).await?;
+        assert_eq!(file.kind(), NodeKind::File);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_unchunked_nodes() -> TestResult {
+        let db = FsDatabase::new(":memory:").await?;
+        let legacy = db
+            .create_node(1, OsStr::new("legacy"), false, OWNER)
+            .await?;
+        sqlx::query("update node set cloud_id='m0', size=10 where id=?")
+            .bind(legacy.id)
+            .execute(&db.connection)
+            .await?;
+        let chunked = db
+            .create_node(1, OsStr::new("chunked"), false, OWNER)
+            .await?;
+        db.set_node_chunks(chunked.id, &[chunk(chunked.id, 0)], 10)
+            .await?;
+
+        let nodes = db.get_unchunked_nodes().await?;
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes[0].id, legacy.id);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_link_node() -> TestResult {
+        let db = FsDatabase::new(":memory:").await?;
+        let dir = db.create_node(1, OsStr::new("dir"), true, OWNER).await?;
+        let file = db.create_node(1, OsStr::new("file"), false, OWNER).await?;
+        db.set_node_chunks(file.id, &[chunk(file.id, 0)], 10)
+            .await?;
+
+        let link = db
+            .link_node(file.id as u64, dir.id as u64, OsStr::new("link"))
+            .await?;
+        assert_eq!(link.id, file.id);
+        assert_eq!(link.nlink, 2);
+        assert!(matches!(
+            db.link_node(file.id as u64, 1, OsStr::new("dir")).await,
+            Err(DbError::Exists(..))
+        ));
+
+        // The node and its chunks outlive the first name
+        let dropped = db.delete_node(1, OsStr::new("file"), false).await?;
+        assert_eq!(dropped, Some(vec![]));
+        let node = db.get_node_by_id(file.id as u64).await?.unwrap();
+        assert_eq!(node.nlink, 1);
+        assert_eq!(db.get_chunks(file.id).await?.len(), 1);
+
+        // Removing the directory drops the last name
+        assert!(matches!(
+            db.delete_node(1, OsStr::new("dir"), true).await,
+            Err(DbError::NotEmpty(id)) if id == dir.id
+        ));
+        let chunks = db.delete_tree(1, OsStr::new("dir")).await?;
+        assert_eq!(chunks.len(), 1);
+        assert!(db.get_node_by_id(file.id as u64).await?.is_none());
+        assert!(db.get_chunks(file.id).await?.is_empty());
+        let root = db.get_node_by_id(1).await?.unwrap();
+        assert_eq!(root.nlink, 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_delete_node_chunks() -> TestResult {
+        let db = FsDatabase::new(":memory:").await?;
+        let file = db.create_node(1, OsStr::new("file"), false, OWNER).await?;
+        db.set_node_chunks(file.id, &[chunk(file.id, 0)], 10)
+            .await?;
+
+        // Dropping the last name hands back the chunks, a missing name isn't an error
+        let dropped = db.delete_node(1, OsStr::new("file"), false).await?;
+        assert_eq!(dropped, Some(vec![chunk(file.id, 0)]));
+        assert_eq!(db.delete_node(1, OsStr::new("file"), false).await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_xattr() -> TestResult {
+        let db = FsDatabase::new(":memory:").await?;
+        let node = db.create_node(1, OsStr::new("file"), false, OWNER).await?;
+
+        db.set_xattr(node.id, "user.b", b"1", XattrMode::Create)
+            .await?;
+        db.set_xattr(node.id, "user.a", b"2", XattrMode::Upsert)
+            .await?;
+        assert!(matches!(
+            db.set_xattr(node.id, "user.a", b"3", XattrMode::Create)
+                .await,
+            Err(DbError::Exists(..))
+        ));
+        assert!(matches!(
+            db.set_xattr(node.id, "user.c", b"3", XattrMode::Replace)
+                .await,
+            Err(DbError::DoesNotExist(..))
+        ));
+        db.set_xattr(node.id, "user.a", b"4", XattrMode::Replace)
+            .await?;
+        assert_eq!(db.get_xattr(node.id, "user.a").await?, Some(b"4".to_vec()));
+        assert_eq!(db.list_xattrs(node.id).await?, vec!["user.a", "user.b"]);
+
+        assert!(db.remove_xattr(node.id, "user.a").await?);
+        assert!(!db.remove_xattr(node.id, "user.a").await?);
+        assert_eq!(db.get_xattr(node.id, "user.a").await?, None);
+
+        // Attributes go with the node
+        db.delete_node(1, OsStr::new("file"), false).await?;
+        assert!(db.list_xattrs(node.id).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sha256_invalidated() -> TestResult {
+        let db = FsDatabase::new(":memory:").await?;
+        let node = db.create_node(1, OsStr::new("file"), false, OWNER).await?;
+
+        db.set_sha256(node.id, "stale", Some(0.0)).await?;
+        assert_eq!(db.get_sha256(node.id).await?, None);
+        db.set_sha256(node.id, "abc", node.mtime).await?;
+        assert_eq!(db.get_sha256(node.id).await?.as_deref(), Some("abc"));
+
+        db.set_node_chunks(node.id, &[chunk(node.id, 0)], 10)
+            .await?;
+        assert_eq!(db.get_sha256(node.id).await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_delete_tree() -> TestResult {
+        let db = FsDatabase::new(":memory:").await?;
+        let dir = db.create_node(1, OsStr::new("dir"), true, OWNER).await?;
+        let sub = db
+            .create_node(dir.id as u64, OsStr::new("sub"), true, OWNER)
+            .await?;
+        let file = db
+            .create_node(sub.id as u64, OsStr::new("file"), false, OWNER)
+            .await?;
+        let linked = db
+            .create_node(sub.id as u64, OsStr::new("linked"), false, OWNER)
+            .await?;
+        db.set_node_chunks(file.id, &[chunk(file.id, 0), chunk(file.id, 1)], 20)
+            .await?;
+        db.set_node_chunks(linked.id, &[chunk(linked.id, 0)], 10)
+            .await?;
+        db.link_node(linked.id as u64, 1, OsStr::new("outside"))
+            .await?;
+
+        assert!(matches!(
+            db.delete_node(dir.id, OsStr::new("sub"), true).await,
+            Err(DbError::NotEmpty(_))
+        ));
+
+        // Looking up the chunks first leaves everything in place
+        let chunks = db.tree_chunks(1, OsStr::new("dir")).await?;
+        assert_eq!(chunks.len(), 2);
+        assert!(db.get_node_by_id(file.id as u64).await?.is_some());
+        assert_eq!(db.get_chunks(file.id).await?.len(), 2);
+
+        // Only content of nodes left without a name is handed back
+        let chunks = db.delete_tree(1, OsStr::new("dir")).await?;
+        assert_eq!(chunks.len(), 2);
+        assert!(chunks.iter().all(|chunk| chunk.node == file.id));
+        for id in [dir.id, sub.id, file.id] {
+            assert!(db.get_node_by_id(id as u64).await?.is_none());
+        }
+        let outside = db.get_node_by_id(linked.id as u64).await?.unwrap();
+        assert_eq!(outside.nlink, 1);
+        assert_eq!(db.get_chunks(linked.id).await?.len(), 1);
+
+        assert!(matches!(
+            db.delete_tree(1, OsStr::new("dir")).await,
+            Err(DbError::DoesNotExist(_))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_move_node() -> TestResult {
+        let db = FsDatabase::new(":memory:").await?;
+        let a = db.create_node(1, OsStr::new("a"), false, OWNER).await?;
+        let b = db.create_node(1, OsStr::new("b"), false, OWNER).await?;
+        db.set_node_chunks(b.id, &[chunk(b.id, 0)], 10).await?;
+        let dir = db.create_node(1, OsStr::new("dir"), true, OWNER).await?;
+        let sub = db
+            .create_node(dir.id as u64, OsStr::new("sub"), true, OWNER)
+            .await?;
+
+        assert!(matches!(
+            db.move_node(
+                1,
+                OsStr::new("a"),
+                1,
+                OsStr::new("b"),
+                RenameMode::NoReplace
+            )
+            .await,
+            Err(DbError::Exists(..))
+        ));
+        assert!(matches!(
+            db.move_node(
+                1,
+                OsStr::new("a"),
+                1,
+                OsStr::new("dir"),
+                RenameMode::Replace
+            )
+            .await,
+            Err(DbError::IsDirectory(_))
+        ));
+        assert!(matches!(
+            db.move_node(
+                1,
+                OsStr::new("dir"),
+                1,
+                OsStr::new("a"),
+                RenameMode::Replace
+            )
+            .await,
+            Err(DbError::NotDirectory(_))
+        ));
+        assert!(matches!(
+            db.move_node(
+                1,
+                OsStr::new("dir"),
+                sub.id,
+                OsStr::new("loop"),
+                RenameMode::Replace
+            )
+            .await,
+            Err(DbError::MoveIntoSelf(_))
+        ));
+        assert!(matches!(
+            db.move_node(
+                1,
+                OsStr::new("missing"),
+                1,
+                OsStr::new("c"),
+                RenameMode::Replace
+            )
+            .await,
+            Err(DbError::DoesNotExist(_))
+        ));
+
+        // Replacing drops the target node along with its last name
+        let dropped = db
+            .move_node(1, OsStr::new("a"), 1, OsStr::new("b"), RenameMode::Replace)
+            .await?;
+        assert_eq!(dropped, vec![chunk(b.id, 0)]);
+        assert_eq!(db.get_node(1, OsStr::new("b")).await?.unwrap().id, a.id);
+        assert!(db.get_node(1, OsStr::new("a")).await?.is_none());
+        assert!(db.get_node_by_id(b.id as u64).await?.is_none());
+
+        // Exchanging swaps the nodes, which may be of different kinds
+        db.move_node(
+            1,
+            OsStr::new("b"),
+            dir.id,
+            OsStr::new("sub"),
+            RenameMode::Exchange,
+        )
+        .await?;
+        assert_eq!(db.get_node(1, OsStr::new("b")).await?.unwrap().id, sub.id);
+        assert_eq!(
+            db.get_node(dir.id as u64, OsStr::new("sub"))
+                .await?
+                .unwrap()
+                .id,
+            a.id
+        );
+        assert!(matches!(
+            db.move_node(
+                1,
+                OsStr::new("b"),
+                1,
+                OsStr::new("missing"),
+                RenameMode::Exchange
+            )
+            .await,
+            Err(DbError::DoesNotExist(_))
+        ));
+        assert!(matches!(
+            db.move_node(
+                1,
+                OsStr::new("dir"),
+                1,
+                OsStr::new("b"),
+                RenameMode::Exchange
+            )
+            .await,
+            Ok(chunks) if chunks.is_empty()
+        ));
+
+        // Only empty directories can be replaced
+        let empty = db.create_node(1, OsStr::new("empty"), true, OWNER).await?;
+        assert!(matches!(
+            db.move_node(
+                1,
+                OsStr::new("empty"),
+                1,
+                OsStr::new("b"),
+                RenameMode::Replace
+            )
+            .await,
+            Err(DbError::NotEmpty(_))
+        ));
+        db.move_node(
+            1,
+            OsStr::new("b"),
+            1,
+            OsStr::new("empty"),
+            RenameMode::Replace,
+        )
+        .await?;
+        assert!(db.get_node_by_id(empty.id as u64).await?.is_none());
+
+        // Two names of one node stay as they are
+        db.link_node(a.id as u64, 1, OsStr::new("link")).await?;
+        db.move_node(
+            dir.id,
+            OsStr::new("sub"),
+            1,
+            OsStr::new("link"),
+            RenameMode::Replace,
+        )
+        .await?;
+        assert_eq!(db.get_node_by_id(a.id as u64).await?.unwrap().nlink, 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_create() -> TestResult {
+        let db = std::sync::Arc::new(FsDatabase::new(":memory:").await?);
+        let tasks: Vec<_> = (0..8)
+            .map(|_| {
+                let db = db.clone();
+                tokio::spawn(
+                    async move { db.create_node(1, OsStr::new("file"), false, OWNER).await },
+                )
+            })
+            .collect();
+        let mut created = 0;
+        for task in tasks {
+            match task.await? {
+                Ok(_) => created += 1,
+                Err(DbError::Exists(..)) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        assert_eq!(created, 1);
+        assert_eq!(db.get_nodes_by_parent(1, 0, 10).await?.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_usage() -> TestResult {
+        let db = FsDatabase::new(":memory:").await?;
+        db.create_node(1, OsStr::new("dir"), true, OWNER).await?;
+        let file = db.create_node(1, OsStr::new("file"), false, OWNER).await?;
+        db.set_node_chunks(file.id, &[chunk(file.id, 0)], 10)
+            .await?;
+        db.create_symlink(1, OsStr::new("link"), b"file", OWNER)
+            .await?;
+
+        let usage = db.get_usage().await?;
+        assert_eq!(usage.bytes, 14);
+        assert_eq!(usage.nodes, 4);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_node_ids_not_reused() -> TestResult {
+        let db = FsDatabase::new(":memory:").await?;
+        let deleted = db.create_node(1, OsStr::new("a"), false, OWNER).await?;
+        db.delete_node(1, OsStr::new("a"), false).await?;
+
+        // Even the highest id stays taken, so staged uploads of the deleted node can't hit the new one
+        let created = db.create_node(1, OsStr::new("d"), true, OWNER).await?;
+        assert!(created.id > deleted.id);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_nodes_by_parent() -> TestResult {
+        let db = FsDatabase::new(":memory:").await?;
+        let mut created = vec![];
+        for name in ["c", "a", "b"] {
+            created.push(db.create_node(1, OsStr::new(name), false, OWNER).await?);
+        }
+
+        // Pages continue after the last entry seen even when earlier ones go away
+        let page = db.get_nodes_by_parent(1, 0, 2).await?;
+        assert_eq!(page.len(), 2);
+        assert_eq!(page[0].file_name(), "c");
+        db.delete_node(1, OsStr::new("c"), false).await?;
+        let page = db
+            .get_nodes_by_parent(1, page[1].dirent.unwrap(), 2)
+            .await?;
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].id, created[2].id);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_non_utf8_names() -> TestResult {
+        let db = FsDatabase::new(":memory:").await?;
+        let latin1 = OsStr::from_bytes(b"caf\xe9");
+        let mangled = OsStr::new("caf\u{fffd}");
+        let node = db.create_node(1, latin1, false, OWNER).await?;
+        assert_eq!(node.file_name(), latin1);
+
+        // Names that only differ in invalid bytes are different names
+        db.create_node(1, OsStr::from_bytes(b"caf\xff"), false, OWNER)
+            .await?;
+        assert!(db.get_node(1, mangled).await?.is_none());
+        assert_eq!(db.get_node(1, latin1).await?.unwrap().id, node.id);
+
+        let renamed = OsStr::from_bytes(b"\x80\x81");
+        db.move_node(1, latin1, 1, renamed, RenameMode::NoReplace)
+            .await?;
+        // Renaming keeps the entry in its place
+        assert_eq!(
+            db.get_nodes_by_parent(1, 0, 10).await?[0].file_name(),
+            renamed
+        );
+        assert!(db.delete_node(1, renamed, false).await?.is_some());
+        assert!(db.get_node_by_id(node.id as u64).await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_name_too_long() -> TestResult {
+        let db = FsDatabase::new(":memory:").await?;
+        let long = "a".repeat(MAX_NAME_LEN + 1);
+        assert!(matches!(
+            db.create_node(1, OsStr::new(&long), false, OWNER).await,
+            Err(DbError::NameTooLong(_))
+        ));
+        let name = "a".repeat(MAX_NAME_LEN);
+        db.create_node(1, OsStr::new(&name), false, OWNER).await?;
+        assert!(matches!(
+            db.move_node(
+                1,
+                OsStr::new(&name),
+                1,
+                OsStr::new(&long),
+                RenameMode::Replace
+            )
+            .await,
+            Err(DbError::NameTooLong(_))
+        ));
+        Ok(())
+    }
 }
@@ -1,6 +1,7 @@
+use libc::c_int;
 use thiserror::Error;
 
-use crate::client::error::ClientError;
+use crate::{client::error::ClientError, error::encryption::EncryptionError};
 
 #[derive(Error, Debug)]
 pub enum DbError {
@@ -16,6 +17,24 @@ pub enum DbError {
     #[error("Node does not exist {0}")]
     DoesNotExist(i64),
 
+    #[error("Directory is not empty: {0}")]
+    NotEmpty(i64),
+
+    #[error("Node is a directory: {0}")]
+    IsDirectory(i64),
+
+    #[error("Node is not a directory: {0}")]
+    NotDirectory(i64),
+
+    #[error("Directory cannot be moved inside itself: {0}")]
+    MoveIntoSelf(i64),
+
+    #[error("Name is too long: {0}")]
+    NameTooLong(String),
+
+    #[error("Database schema version {0} is newer than this build supports")]
+    SchemaTooNew(i64),
+
     #[error("Other error: {0}")]
     Other(String),
 }
@@ -33,6 +52,63 @@ pub enum FsError {
 
     #[error("Client error: {0}")]
     ClientError(#[from] ClientError),
+
+    #[error("No space left under the configured capacity")]
+    NoSpace,
+}
+
+impl DbError {
+    /// Error number to reply to the kernel with
+    pub fn errno(&self) -> c_int {
+        match self {
+            Self::SqlxError(sqlx::Error::RowNotFound) => libc::ENOENT,
+            Self::SqlxError(sqlx::Error::Database(e)) if e.is_unique_violation() => libc::EEXIST,
+            Self::Exists(_, _) => libc::EEXIST,
+            Self::DoesNotExist(_) => libc::ENOENT,
+            Self::NotEmpty(_) => libc::ENOTEMPTY,
+            Self::IsDirectory(_) => libc::EISDIR,
+            Self::NotDirectory(_) => libc::ENOTDIR,
+            Self::MoveIntoSelf(_) => libc::EINVAL,
+            Self::NameTooLong(_) => libc::ENAMETOOLONG,
+            Self::ConnectionError(_)
+            | Self::SqlxError(_)
+            | Self::SchemaTooNew(_)
+            | Self::Other(_) => libc::EIO,
+        }
+    }
+}
+
+impl FsError {
+    /// Error number to reply to the kernel with
+    pub fn errno(&self) -> c_int {
+        match self {
+            Self::DatabaseError(e) => e.errno(),
+            Self::ClientError(e) => e.errno(),
+            Self::NoSpace => libc::ENOSPC,
+            Self::RuntimeError(_) | Self::TimeError(_) => libc::EIO,
+        }
+    }
+}
+
+/// Error number for an error that went through the io traits, looking inside for our own errors
+pub fn io_errno(error: &std::io::Error) -> c_int {
+    if let Some(errno) = error.raw_os_error() {
+        return errno;
+    }
+    let Some(inner) = error.get_ref() else {
+        return libc::EIO;
+    };
+    if let Some(e) = inner.downcast_ref::<FsError>() {
+        e.errno()
+    } else if let Some(e) = inner.downcast_ref::<DbError>() {
+        e.errno()
+    } else if let Some(e) = inner.downcast_ref::<ClientError>() {
+        e.errno()
+    } else if let Some(e) = inner.downcast_ref::<EncryptionError>() {
+        e.errno()
+    } else {
+        libc::EIO
+    }
 }
 
 impl From<DbError> for std::io::Error {
@@ -40,3 +116,67 @@ impl From<DbError> for std::io::Error {
         std::io::Error::new(std::io::ErrorKind::Other, value)
     }
 }
+
+impl From<FsError> for std::io::Error {
+    fn from(value: FsError) -> Self {
+        std::io::Error::other(value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_errno() {
+        assert_eq!(DbError::DoesNotExist(2).errno(), libc::ENOENT);
+        assert_eq!(DbError::Exists(2, "a".to_string()).errno(), libc::EEXIST);
+        assert_eq!(DbError::NotEmpty(2).errno(), libc::ENOTEMPTY);
+        assert_eq!(DbError::IsDirectory(2).errno(), libc::EISDIR);
+        assert_eq!(DbError::NotDirectory(2).errno(), libc::ENOTDIR);
+        assert_eq!(DbError::MoveIntoSelf(2).errno(), libc::EINVAL);
+        assert_eq!(
+            DbError::NameTooLong("a".repeat(256)).errno(),
+            libc::ENAMETOOLONG
+        );
+        assert_eq!(
+            DbError::SqlxError(sqlx::Error::RowNotFound).errno(),
+            libc::ENOENT
+        );
+        assert_eq!(DbError::Other("".to_string()).errno(), libc::EIO);
+
+        assert_eq!(FsError::NoSpace.errno(), libc::ENOSPC);
+        assert_eq!(
+            FsError::DatabaseError(DbError::NotEmpty(2)).errno(),
+            libc::ENOTEMPTY
+        );
+        assert_eq!(FsError::TimeError("".to_string()).errno(), libc::EIO);
+
+        // Failed decryption means the content was tampered with or the key is wrong
+        let decryption = ClientError::EncryptionError(EncryptionError::AesUnknown);
+        assert_eq!(decryption.errno(), libc::EBADMSG);
+        assert_eq!(FsError::ClientError(decryption).errno(), libc::EBADMSG);
+        assert_eq!(ClientError::RequestValue("".to_string()).errno(), libc::EIO);
+        assert_eq!(
+            EncryptionError::InvalidKey("".to_string()).errno(),
+            libc::EIO
+        );
+    }
+
+    #[test]
+    fn test_io_errno() {
+        assert_eq!(io_errno(&DbError::NotEmpty(2).into()), libc::ENOTEMPTY);
+        assert_eq!(io_errno(&FsError::NoSpace.into()), libc::ENOSPC);
+        let decryption = ClientError::EncryptionError(EncryptionError::AesUnknown);
+        assert_eq!(io_errno(&decryption.into()), libc::EBADMSG);
+        assert_eq!(io_errno(&EncryptionError::AesUnknown.into()), libc::EBADMSG);
+        assert_eq!(
+            io_errno(&std::io::Error::from_raw_os_error(libc::EBADF)),
+            libc::EBADF
+        );
+        assert_eq!(
+            io_errno(&std::io::Error::other("unknown".to_string())),
+            libc::EIO
+        );
+    }
+}
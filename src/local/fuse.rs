@@ -1,22 +1,60 @@
-use std::{collections::HashMap, sync::Arc, time::Duration};
+use std::{
+    cmp::max,
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    io::SeekFrom,
+    os::unix::ffi::OsStrExt,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
-use fuser::{FileType, Filesystem};
-use libc::{c_int, EEXIST, ENOENT, EPERM};
-use log::{debug, error, info, trace};
+use fuser::{
+    consts::{FUSE_DO_READDIRPLUS, FUSE_POSIX_LOCKS, FUSE_READDIRPLUS_AUTO},
+    FileAttr, Filesystem, KernelConfig, TimeOrNow,
+};
+use libc::{
+    c_int, EAGAIN, EBADF, EINTR, EINVAL, EISDIR, ENODATA, ENOENT, ENOTDIR, EPERM, ERANGE,
+    RENAME_EXCHANGE, RENAME_NOREPLACE,
+};
+use log::{debug, error, info, trace, warn};
 use tokio::{runtime::Handle, sync::Mutex};
 
 use crate::{
     client::{
-        client::{CloudClient, CloudRead, CloudWrite},
+        client::{ClientOptions, CloudClient, WriteOptions},
         discord::client::DiscordClient,
     },
     local::error::DbError,
-    util::fs::attrs_from_node,
+    util::{
+        async_file::{AsyncRead, AsyncSeek},
+        fs::{attrs_from_node, kind_from_node},
+    },
+};
+
+use super::{
+    db::{
+        FsChunk, FsDatabase, FsNode, NodeAttributes, NodeKind, NodeOwner, RenameMode, XattrMode,
+        MAX_NAME_LEN,
+    },
+    error::{io_errno, FsError},
+    handle::{FileHandle, HandleTable},
+    lock::{Lock, LockKind, LockTable},
+    usage::Usage,
 };
 
-use super::{db::FsDatabase, error::FsError};
+/// Offsets taken by `.` and `..` before the directory entry ids
+const DOT_ENTRIES: i64 = 2;
+/// Entries fetched per readdir call, the kernel asks again until it gets none
+const READDIR_BATCH: i64 = 256;
+
+/// Reported when no capacity is configured, since the cloud has no real limit
+const DEFAULT_CAPACITY: u64 = 1 << 50;
 
-const EUNKNOWN: c_int = 99;
+/// Read-only attributes describing where a node is stored
+const VIRTUAL_XATTR_PREFIX: &str = "user.discfs.";
+const XATTR_MESSAGE_ID: &str = "user.discfs.message_id";
+const XATTR_CHUNKS: &str = "user.discfs.chunks";
+const XATTR_SHA256: &str = "user.discfs.sha256";
 
 // Unused open flags
 // const FOPEN_DIRECT_IO: u32 = 1 << 0;
@@ -33,10 +71,15 @@ pub struct DiscFs {
 }
 
 pub struct DiscFsInner {
-    pub write_handles: Arc<Mutex<HashMap<u64, Box<dyn CloudWrite>>>>,
-    pub read_handles: Arc<Mutex<HashMap<u64, Box<dyn CloudRead>>>>,
+    pub handles: HandleTable,
+    pub locks: LockTable,
     pub db: Arc<FsDatabase>,
     pub client: Box<dyn CloudClient>,
+    pub usage: Usage,
+    /// Chunks of removed nodes still open for writing, deleted once their last handle goes.
+    /// Held while finding out whether a removed node's space can be given back,
+    /// so removing its last name and closing its last writer don't both give it back
+    removals: Mutex<HashMap<u64, Vec<FsChunk>>>,
 }
 enum OpenMode {
     Read,
@@ -44,25 +87,313 @@ enum OpenMode {
     ReadWrite,
 }
 impl DiscFs {
-    pub fn new(rt: Handle, db: FsDatabase, ctype: CloudType) -> Result<Self, FsError> {
+    pub fn new(
+        rt: Handle,
+        db: FsDatabase,
+        ctype: CloudType,
+        options: ClientOptions,
+        capacity: Option<u64>,
+    ) -> Result<Self, FsError> {
         let db = Arc::new(db);
+        let client: Box<dyn CloudClient> = Box::new(match ctype {
+            CloudType::Discord => DiscordClient::new(rt.clone(), db.clone(), &options)?,
+        });
+
+        // Done before mounting since opening a node starts a new staged file over the old one
+        if let Err(e) = rt.block_on(client.resume()) {
+            error!("error resuming staged uploads: {:?}", e);
+        }
+        let used = rt.block_on(Self::initial_usage(&db, client.as_ref()))?;
+
         let inner = DiscFsInner {
-            db: db.clone(),
-            client: Box::new(match ctype {
-                CloudType::Discord => DiscordClient::new(rt.clone(), db)?,
-            }),
-            write_handles: Arc::new(Mutex::new(HashMap::new())),
-            read_handles: Arc::new(Mutex::new(HashMap::new())),
+            db,
+            client,
+            handles: HandleTable::new(),
+            locks: LockTable::new(),
+            usage: Usage::new(used, capacity),
+            removals: Mutex::new(HashMap::new()),
+        };
+        let inner = Arc::new(inner);
+
+        // Files that are opened before their turn get migrated on demand
+        let migrate_inner = inner.clone();
+        rt.spawn(async move {
+            if let Err(e) = migrate_inner.client.migrate().await {
+                error!("error migrating cloud metadata: {:?}", e);
+            }
+        });
+
+        Ok(Self { rt, inner })
+    }
+
+    /// Committed sizes, corrected for staged files an earlier run left to upload
+    async fn initial_usage(db: &FsDatabase, client: &dyn CloudClient) -> Result<u64, FsError> {
+        let mut used = db.get_usage().await?.bytes;
+        for (id, size) in client.staged_sizes().await {
+            let committed = db
+                .get_node_by_id(id as u64)
+                .await?
+                .and_then(|node| node.size)
+                .unwrap_or(0);
+            used += size as i64 - committed;
+        }
+        Ok(max(used, 0) as u64)
+    }
+
+    fn owner(req: &fuser::Request<'_>, mode: u32, umask: u32) -> NodeOwner {
+        NodeOwner {
+            mode: mode & !umask & 0o7777,
+            uid: req.uid(),
+            gid: req.gid(),
+        }
+    }
+
+    async fn node_attrs(inner: &DiscFsInner, node: &FsNode) -> Result<FileAttr, FsError> {
+        let mut attrs = attrs_from_node(node)?;
+        // Files being written can differ in size from what the database knows
+        if let Some(file) = inner.handles.writer(attrs.ino).await {
+            attrs.size = file.lock().await.size();
+        } else if let Some(size) = inner.client.staged_size(node.id).await {
+            attrs.size = size;
+        }
+        Ok(attrs)
+    }
+
+    /// Resizes through the open writer if there is one so its unflushed writes aren't lost
+    async fn truncate(inner: &DiscFsInner, node: FsNode, size: u64) -> std::io::Result<()> {
+        let ino = node.id as u64;
+        let options = WriteOptions {
+            truncate: size == 0,
+        };
+        let open = || inner.client.open_file_write(node, options);
+        let (fh, file, _) = inner.handles.insert_write(ino, false, open).await?;
+        let result = file.lock().await.set_len(size).await;
+        Self::release_handle(inner, fh).await?;
+        result
+    }
+
+    /// Closes a handle, committing the writer if no other handle uses it.
+    /// A file whose last name went away while it was open gives back its space here
+    async fn release_handle(inner: &DiscFsInner, fh: u64) -> std::io::Result<()> {
+        let mut removals = inner.removals.lock().await;
+        match inner.handles.remove(fh).await {
+            Some((FileHandle::Write { ino, .. }, Some(file))) => {
+                let mut file = file.lock().await;
+                let mut unused = vec![];
+                if let Ok(None) = inner.db.get_node_by_id(ino).await {
+                    inner.usage.release(file.size());
+                    unused = removals.remove(&ino).unwrap_or_default();
+                }
+                drop(removals);
+                // What is left to upload is all in the staged file by now
+                Self::discard(inner, &unused).await;
+                file.flush().await?;
+                file.finish();
+            }
+            Some((FileHandle::Read(file), _)) => file.lock().await.finish(),
+            Some((FileHandle::Write { .. }, None)) => {}
+            None => return Err(std::io::Error::from_raw_os_error(EBADF)),
+        }
+        Ok(())
+    }
+
+    /// Node behind a name and the space it takes, looked up before the name is removed
+    async fn sized_node(inner: &DiscFsInner, parent: u64, name: &OsStr) -> Option<(u64, u64)> {
+        let node = inner.db.get_node(parent, name).await.ok()??;
+        let attrs = Self::node_attrs(inner, &node).await.ok()?;
+        Some((attrs.ino, attrs.size))
+    }
+
+    /// Gives back the space of a node once its last name is gone, returning the chunks of a
+    /// dropped node that can be deleted now. Files still open for writing keep both until their
+    /// last handle is released. `removals` has to be held since before the name was removed
+    async fn release_removed(
+        inner: &DiscFsInner,
+        removals: &mut HashMap<u64, Vec<FsChunk>>,
+        removed: Option<(u64, u64)>,
+        chunks: Vec<FsChunk>,
+    ) -> Vec<FsChunk> {
+        let Some((ino, size)) = removed else {
+            return chunks;
+        };
+        if inner.handles.writer(ino).await.is_some() {
+            if !chunks.is_empty() {
+                removals.insert(ino, chunks);
+            }
+            return vec![];
+        }
+        if let Ok(None) = inner.db.get_node_by_id(ino).await {
+            inner.usage.release(size);
+        }
+        chunks
+    }
+
+    /// Deletes the uploaded content of removed nodes
+    async fn discard(inner: &DiscFsInner, chunks: &[FsChunk]) {
+        if chunks.is_empty() {
+            return;
+        }
+        if let Err(e) = inner.client.delete_chunks(chunks).await {
+            warn!("error deleting content of removed node: {:?}", e);
+        }
+    }
+
+    /// Hands everything written to a file over for uploading
+    async fn flush_handle(inner: &DiscFsInner, fh: u64) -> std::io::Result<()> {
+        match inner.handles.get(fh).await {
+            Some(FileHandle::Write { file, .. }) => file.lock().await.flush().await,
+            Some(FileHandle::Read(_)) => Ok(()),
+            None => Err(std::io::Error::from_raw_os_error(EBADF)),
+        }
+    }
+
+    /// Uploads everything written to a file and commits its chunks to the database
+    async fn sync(inner: &DiscFsInner, fh: u64) -> std::io::Result<()> {
+        match inner.handles.get(fh).await {
+            Some(FileHandle::Write { file, .. }) => file.lock().await.sync().await,
+            Some(FileHandle::Read(_)) => Ok(()),
+            None => Err(std::io::Error::from_raw_os_error(EBADF)),
+        }
+    }
+
+    async fn read_handle<F: AsyncRead + AsyncSeek + ?Sized>(
+        handle: &mut F,
+        position: SeekFrom,
+        buffer: &mut [u8],
+    ) -> std::io::Result<usize> {
+        handle.seek(position).await?;
+        handle.read(buffer).await
+    }
+
+    fn virtual_xattr_names(node: &FsNode) -> Vec<&'static str> {
+        let mut names = vec![];
+        if node.cloud_id.is_some() {
+            names.push(XATTR_MESSAGE_ID);
+        }
+        if node.kind() == NodeKind::File {
+            names.push(XATTR_CHUNKS);
+            names.push(XATTR_SHA256);
+        }
+        names
+    }
+
+    async fn virtual_xattr(
+        inner: &DiscFsInner,
+        node: FsNode,
+        name: &str,
+    ) -> Result<Option<Vec<u8>>, FsError> {
+        if !Self::virtual_xattr_names(&node).contains(&name) {
+            return Ok(None);
+        }
+        let value = match name {
+            XATTR_MESSAGE_ID => node.cloud_id.unwrap_or_default().into_bytes(),
+            XATTR_CHUNKS => {
+                // One line per chunk
+                let mut value = String::new();
+                for chunk in inner.db.get_chunks(node.id).await? {
+                    value.push_str(&format!(
+                        "{} {} {} {}\n",
+                        chunk.idx, chunk.message_id, chunk.attachment_id, chunk.size
+                    ));
+                }
+                value.into_bytes()
+            }
+            XATTR_SHA256 => Self::content_sha256(inner, node).await?.into_bytes(),
+            _ => return Ok(None),
+        };
+        Ok(Some(value))
+    }
+
+    /// Hashes the committed content, downloading it the first time
+    async fn content_sha256(inner: &DiscFsInner, node: FsNode) -> Result<String, FsError> {
+        if let Some(hash) = inner.db.get_sha256(node.id).await? {
+            return Ok(hash);
+        }
+        let (id, mtime) = (node.id, node.mtime);
+        let mut context = ring::digest::Context::new(&ring::digest::SHA256);
+        if node.size.unwrap_or(0) > 0 {
+            let mut file = inner.client.open_file_read(node).await?;
+            let mut buffer = vec![0; 1024 * 1024];
+            loop {
+                let read = file
+                    .read(&mut buffer)
+                    .await
+                    .map_err(|e| FsError::RuntimeError(e.to_string()))?;
+                if read == 0 {
+                    break;
+                }
+                context.update(&buffer[..read]);
+            }
+        }
+        let hash: String = context
+            .finish()
+            .as_ref()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        inner.db.set_sha256(id, &hash, mtime).await?;
+        Ok(hash)
+    }
+
+    /// Replies with the size when asked for it, otherwise the data if it fits
+    fn reply_xattr(reply: fuser::ReplyXattr, size: u32, data: &[u8]) {
+        if size == 0 {
+            reply.size(data.len() as u32);
+        } else if data.len() > size as usize {
+            reply.error(ERANGE);
+        } else {
+            reply.data(data);
+        }
+    }
+
+    /// Entries after a readdir offset, starting with `.` and `..`.
+    /// Offsets come from directory entry ids so they stay valid while entries are added or removed
+    async fn dir_entries(
+        inner: &DiscFsInner,
+        ino: u64,
+        offset: i64,
+    ) -> Result<Vec<(i64, OsString, FsNode)>, c_int> {
+        let dir = match inner.db.get_node_by_id(ino).await {
+            Ok(Some(dir)) if dir.directory => dir,
+            Ok(Some(_)) => return Err(ENOTDIR),
+            Ok(None) => return Err(ENOENT),
+            Err(e) => return Err(e.errno()),
         };
-        Ok(Self {
-            rt,
-            inner: Arc::new(inner),
-        })
+        let mut entries = vec![];
+        if offset < 1 {
+            entries.push((1, OsString::from("."), dir.clone()));
+        }
+        if offset < 2 {
+            let parent = match dir.parent {
+                Some(parent) => inner.db.get_node_by_id(parent as u64).await,
+                // The root is its own parent
+                None => Ok(Some(dir)),
+            };
+            match parent {
+                Ok(Some(parent)) => entries.push((2, OsString::from(".."), parent)),
+                Ok(None) => return Err(ENOENT),
+                Err(e) => return Err(e.errno()),
+            }
+        }
+        let after = max(offset - DOT_ENTRIES, 0);
+        let children = inner
+            .db
+            .get_nodes_by_parent(ino as i64, after, READDIR_BATCH)
+            .await
+            .map_err(|e| e.errno())?;
+        for node in children {
+            let offset = node.dirent.unwrap_or_default() + DOT_ENTRIES;
+            entries.push((offset, node.file_name().to_owned(), node));
+        }
+        Ok(entries)
     }
 
-    fn is_write(flags: i32) -> bool {
-        let write_flags = libc::O_RDWR | libc::O_WRONLY;
-        (flags & write_flags) > 0
+    fn lock_kind(typ: i32) -> Option<LockKind> {
+        match typ {
+            libc::F_RDLCK => Some(LockKind::Read),
+            libc::F_WRLCK => Some(LockKind::Write),
+            _ => None,
+        }
     }
 
     fn get_mode(flags: i32) -> OpenMode {
@@ -77,6 +408,22 @@ impl DiscFs {
 }
 
 impl Filesystem for DiscFs {
+    fn init(&mut self, _req: &fuser::Request<'_>, config: &mut KernelConfig) -> Result<(), c_int> {
+        // POSIX locks go through our table. flock locks stay in the kernel, which keeps them
+        // apart from POSIX ones as Linux does; setlk isn't told which kind a request is
+        if config.add_capabilities(FUSE_POSIX_LOCKS).is_err() {
+            warn!("kernel does not support remote POSIX locks");
+        }
+        // Listings come with attributes so `ls -l` doesn't look up every entry
+        if config
+            .add_capabilities(FUSE_DO_READDIRPLUS | FUSE_READDIRPLUS_AUTO)
+            .is_err()
+        {
+            warn!("kernel does not support readdirplus");
+        }
+        Ok(())
+    }
+
     fn lookup(
         &mut self,
         _req: &fuser::Request<'_>,
@@ -92,23 +439,20 @@ impl Filesystem for DiscFs {
             let node = inner.db.get_node(parent, &name_cp).await;
             match node {
                 Ok(n) => match n {
-                    Some(n) => {
-                        if let Ok(attrs) = &attrs_from_node(&n) {
-                            reply.entry(&Duration::from_millis(64), attrs, 0)
-                        } else {
-                            reply.error(EUNKNOWN)
-                        }
-                    }
+                    Some(n) => match Self::node_attrs(&inner, &n).await {
+                        Ok(attrs) => reply.entry(&Duration::from_millis(64), &attrs, 0),
+                        Err(e) => reply.error(e.errno()),
+                    },
                     None => reply.error(ENOENT),
                 },
-                Err(_) => reply.error(EUNKNOWN),
+                Err(e) => reply.error(e.errno()),
             };
         });
     }
 
     fn mkdir(
         &mut self,
-        _req: &fuser::Request<'_>,
+        req: &fuser::Request<'_>,
         parent: u64,
         name: &std::ffi::OsStr,
         mode: u32,
@@ -122,28 +466,275 @@ impl Filesystem for DiscFs {
         info!("create directory: {:?}", name);
         let inner = self.inner.clone();
         let name = name.to_owned();
+        let owner = Self::owner(req, mode, umask);
         self.rt.spawn(async move {
             let name = name.to_owned();
-            let node = inner.db.create_node(parent, &name, true).await;
+            let node = inner.db.create_node(parent, &name, true, owner).await;
             match node {
-                Ok(n) => {
-                    if let Ok(attrs) = &attrs_from_node(&n) {
-                        reply.entry(&Duration::from_millis(64), attrs, 0)
-                    } else {
-                        reply.error(EUNKNOWN)
+                Ok(n) => match Self::node_attrs(&inner, &n).await {
+                    Ok(attrs) => reply.entry(&Duration::from_millis(64), &attrs, 0),
+                    Err(e) => reply.error(e.errno()),
+                },
+                Err(e) => reply.error(e.errno()),
+            }
+        });
+    }
+
+    fn symlink(
+        &mut self,
+        req: &fuser::Request<'_>,
+        parent: u64,
+        link_name: &std::ffi::OsStr,
+        target: &std::path::Path,
+        reply: fuser::ReplyEntry,
+    ) {
+        debug!(
+            "symlink(parent: {:#x?}, link_name: {:?}, target: {:?})",
+            parent, link_name, target
+        );
+        let inner = self.inner.clone();
+        let name = link_name.to_owned();
+        let target = target.as_os_str().as_bytes().to_owned();
+        let owner = Self::owner(req, 0o777, 0);
+        self.rt.spawn(async move {
+            // Targets count towards the usage like they do in the database
+            let size = target.len() as u64;
+            if let Err(e) = inner.usage.reserve(size) {
+                return reply.error(e.errno());
+            }
+            let node = inner.db.create_symlink(parent, &name, &target, owner).await;
+            if node.is_err() {
+                inner.usage.release(size);
+            }
+            match node {
+                Ok(n) => match Self::node_attrs(&inner, &n).await {
+                    Ok(attrs) => reply.entry(&Duration::from_millis(64), &attrs, 0),
+                    Err(e) => {
+                        error!("error in symlink: {:?}", e);
+                        reply.error(e.errno())
                     }
+                },
+                Err(e) => reply.error(e.errno()),
+            }
+        });
+    }
+
+    fn readlink(&mut self, _req: &fuser::Request<'_>, ino: u64, reply: fuser::ReplyData) {
+        let inner = self.inner.clone();
+        self.rt.spawn(async move {
+            match inner.db.get_node_by_id(ino).await {
+                Ok(Some(node)) => match node.target {
+                    Some(target) => reply.data(&target),
+                    None => reply.error(EINVAL),
+                },
+                Ok(None) => reply.error(ENOENT),
+                Err(e) => reply.error(e.errno()),
+            }
+        });
+    }
+
+    fn link(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        ino: u64,
+        newparent: u64,
+        newname: &std::ffi::OsStr,
+        reply: fuser::ReplyEntry,
+    ) {
+        debug!(
+            "link(ino: {:#x?}, newparent: {:#x?}, newname: {:?})",
+            ino, newparent, newname
+        );
+        let inner = self.inner.clone();
+        let name = newname.to_owned();
+        self.rt.spawn(async move {
+            match inner.db.get_node_by_id(ino).await {
+                Ok(Some(node)) if node.directory => {
+                    reply.error(EPERM);
+                    return;
                 }
-                Err(e) => match e {
-                    crate::local::error::DbError::Exists(_, _) => reply.error(EEXIST),
-                    _ => reply.error(EUNKNOWN),
+                Ok(Some(_)) => {}
+                Ok(None) => {
+                    reply.error(ENOENT);
+                    return;
+                }
+                Err(e) => {
+                    reply.error(e.errno());
+                    return;
+                }
+            }
+            match inner.db.link_node(ino, newparent, &name).await {
+                Ok(node) => match Self::node_attrs(&inner, &node).await {
+                    Ok(attrs) => reply.entry(&Duration::from_millis(64), &attrs, 0),
+                    Err(e) => {
+                        error!("error in link: {:?}", e);
+                        reply.error(e.errno())
+                    }
                 },
+                Err(e) => reply.error(e.errno()),
             }
         });
     }
 
-    fn create(
+    fn statfs(&mut self, _req: &fuser::Request<'_>, _ino: u64, reply: fuser::ReplyStatfs) {
+        let inner = self.inner.clone();
+        self.rt.spawn(async move {
+            let usage = match inner.db.get_usage().await {
+                Ok(usage) => usage,
+                Err(e) => {
+                    error!("error in statfs: {:?}", e);
+                    return reply.error(e.errno());
+                }
+            };
+            let block_size = inner.client.block_size();
+            let used = inner.usage.used();
+            let capacity = inner
+                .usage
+                .capacity()
+                .unwrap_or_else(|| used.saturating_add(DEFAULT_CAPACITY));
+            let blocks = capacity.div_ceil(block_size);
+            let free = blocks.saturating_sub(used.div_ceil(block_size));
+            reply.statfs(
+                blocks,
+                free,
+                free,
+                usage.nodes as u64,
+                u64::MAX - usage.nodes as u64,
+                block_size as u32,
+                MAX_NAME_LEN as u32,
+                block_size as u32,
+            );
+        });
+    }
+
+    fn getxattr(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        ino: u64,
+        name: &std::ffi::OsStr,
+        size: u32,
+        reply: fuser::ReplyXattr,
+    ) {
+        let inner = self.inner.clone();
+        let name = name.to_string_lossy().to_string();
+        self.rt.spawn(async move {
+            let node = match inner.db.get_node_by_id(ino).await {
+                Ok(Some(node)) => node,
+                Ok(None) => return reply.error(ENOENT),
+                Err(e) => return reply.error(e.errno()),
+            };
+            let value = if name.starts_with(VIRTUAL_XATTR_PREFIX) {
+                Self::virtual_xattr(&inner, node, &name).await
+            } else {
+                inner
+                    .db
+                    .get_xattr(node.id, &name)
+                    .await
+                    .map_err(FsError::from)
+            };
+            match value {
+                Ok(Some(value)) => Self::reply_xattr(reply, size, &value),
+                Ok(None) => reply.error(ENODATA),
+                Err(e) => {
+                    error!("error in getxattr: {:?}", e);
+                    reply.error(e.errno())
+                }
+            }
+        });
+    }
+
+    fn listxattr(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        ino: u64,
+        size: u32,
+        reply: fuser::ReplyXattr,
+    ) {
+        let inner = self.inner.clone();
+        self.rt.spawn(async move {
+            let node = match inner.db.get_node_by_id(ino).await {
+                Ok(Some(node)) => node,
+                Ok(None) => return reply.error(ENOENT),
+                Err(e) => return reply.error(e.errno()),
+            };
+            let names = match inner.db.list_xattrs(node.id).await {
+                Ok(names) => names,
+                Err(e) => return reply.error(e.errno()),
+            };
+            // Virtual attributes are left out so copying every listed attribute,
+            // as `cp -a` does, doesn't try to set them on the copy
+            let mut data = vec![];
+            for name in &names {
+                data.extend_from_slice(name.as_bytes());
+                data.push(0);
+            }
+            Self::reply_xattr(reply, size, &data);
+        });
+    }
+
+    fn setxattr(
         &mut self,
         _req: &fuser::Request<'_>,
+        ino: u64,
+        name: &std::ffi::OsStr,
+        value: &[u8],
+        flags: i32,
+        _position: u32,
+        reply: fuser::ReplyEmpty,
+    ) {
+        let name = name.to_string_lossy().to_string();
+        if name.starts_with(VIRTUAL_XATTR_PREFIX) {
+            reply.error(EPERM);
+            return;
+        }
+        let mode = if flags & libc::XATTR_CREATE != 0 {
+            XattrMode::Create
+        } else if flags & libc::XATTR_REPLACE != 0 {
+            XattrMode::Replace
+        } else {
+            XattrMode::Upsert
+        };
+        let inner = self.inner.clone();
+        let value = value.to_owned();
+        self.rt.spawn(async move {
+            match inner.db.get_node_by_id(ino).await {
+                Ok(Some(_)) => {}
+                Ok(None) => return reply.error(ENOENT),
+                Err(e) => return reply.error(e.errno()),
+            }
+            match inner.db.set_xattr(ino as i64, &name, &value, mode).await {
+                Ok(()) => reply.ok(),
+                Err(DbError::DoesNotExist(_)) => reply.error(ENODATA),
+                Err(e) => reply.error(e.errno()),
+            }
+        });
+    }
+
+    fn removexattr(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        ino: u64,
+        name: &std::ffi::OsStr,
+        reply: fuser::ReplyEmpty,
+    ) {
+        let name = name.to_string_lossy().to_string();
+        if name.starts_with(VIRTUAL_XATTR_PREFIX) {
+            reply.error(EPERM);
+            return;
+        }
+        let inner = self.inner.clone();
+        self.rt.spawn(async move {
+            match inner.db.remove_xattr(ino as i64, &name).await {
+                Ok(true) => reply.ok(),
+                Ok(false) => reply.error(ENODATA),
+                Err(e) => reply.error(e.errno()),
+            }
+        });
+    }
+
+    fn create(
+        &mut self,
+        req: &fuser::Request<'_>,
         parent: u64,
         name: &std::ffi::OsStr,
         mode: u32,
@@ -153,44 +744,45 @@ impl Filesystem for DiscFs {
     ) {
         let inner = self.inner.clone();
         let name = name.to_owned();
+        let owner = Self::owner(req, mode, umask);
         self.rt.spawn(async move {
-            let mode = Self::get_mode(flags);
-            match mode {
-                OpenMode::Write => {}
-                _ => {
-                    trace!("can't read created node");
-                    reply.error(EPERM);
-                    return;
-                }
+            if let OpenMode::Read = Self::get_mode(flags) {
+                trace!("can't read created node");
+                reply.error(EPERM);
+                return;
             };
-            let node = inner.db.create_node(parent, &name, false).await;
+            let node = inner.db.create_node(parent, &name, false, owner).await;
             match node {
                 Ok(n) => {
-                    let attrs = attrs_from_node(&n);
+                    let attrs = Self::node_attrs(&inner, &n).await;
                     match attrs {
                         Ok(attrs) => {
-                            info!(
-                                "create file: {}",
-                                n.name.as_ref().unwrap_or(&"".to_string())
-                            );
-                            let id = n.id;
-                            let file = inner.client.open_file_write(n).await;
-                            let _already_open =
-                                inner.write_handles.lock().await.insert(id as u64, file);
-                            reply.created(&Duration::from_millis(64), &attrs, 0, 0, 0);
+                            info!("create file: {:?}", n.file_name());
+                            let id = n.id as u64;
+                            let append = flags & libc::O_APPEND != 0;
+                            let options = WriteOptions { truncate: true };
+                            let open = || inner.client.open_file_write(n, options);
+                            match inner.handles.insert_write(id, append, open).await {
+                                Ok((fh, _, _)) => {
+                                    reply.created(&Duration::from_millis(64), &attrs, 0, fh, 0);
+                                }
+                                Err(e) => {
+                                    error!("error opening created file: {:?}", e);
+                                    reply.error(e.errno())
+                                }
+                            }
                         }
-                        Err(_) => reply.error(EUNKNOWN),
+                        Err(e) => reply.error(e.errno()),
                     }
                 }
-                Err(DbError::Exists(_, _)) => reply.error(EEXIST),
-                _ => reply.error(EUNKNOWN),
+                Err(e) => reply.error(e.errno()),
             }
         });
     }
 
     fn mknod(
         &mut self,
-        _req: &fuser::Request<'_>,
+        req: &fuser::Request<'_>,
         parent: u64,
         name: &std::ffi::OsStr,
         mode: u32,
@@ -205,26 +797,24 @@ impl Filesystem for DiscFs {
         );
         // Block creation of Zone.Identifier files from Windows cause that shit's annoying
         if name.to_string_lossy().ends_with("Zone.Identifier") {
-            reply.error(EUNKNOWN);
+            reply.error(EPERM);
             return;
         }
 
         let inner = self.inner.clone();
         let name = name.to_owned();
+        let owner = Self::owner(req, mode, umask);
         self.rt.spawn(async move {
-            let node = inner.db.create_node(parent, &name, false).await;
+            let node = inner.db.create_node(parent, &name, false, owner).await;
             match node {
-                Ok(n) => match attrs_from_node(&n) {
+                Ok(n) => match Self::node_attrs(&inner, &n).await {
                     Ok(attrs) => reply.entry(&Duration::from_millis(64), &attrs, 0),
                     Err(e) => {
                         error!("error in mknod: {:?}", e);
-                        reply.error(EUNKNOWN)
+                        reply.error(e.errno())
                     }
                 },
-                Err(e) => match e {
-                    DbError::Exists(_, _) => reply.error(EEXIST),
-                    _ => reply.error(EUNKNOWN),
-                },
+                Err(e) => reply.error(e.errno()),
             }
         });
     }
@@ -236,33 +826,62 @@ impl Filesystem for DiscFs {
             match node {
                 Ok(n) => match n {
                     Some(n) => match Self::get_mode(flags) {
-                        OpenMode::Write => {
-                            if inner.write_handles.lock().await.contains_key(&ino) {
-                                reply.error(EEXIST);
-                                return;
+                        OpenMode::Write | OpenMode::ReadWrite => {
+                            info!("write file: {:?}", n.file_name());
+                            let truncate = flags & libc::O_TRUNC != 0;
+                            let append = flags & libc::O_APPEND != 0;
+                            // Truncating gives back what the file took up until now
+                            let freed = match truncate {
+                                true => match Self::node_attrs(&inner, &n).await {
+                                    Ok(attrs) => attrs.size,
+                                    Err(e) => return reply.error(e.errno()),
+                                },
+                                false => 0,
+                            };
+                            let options = WriteOptions { truncate };
+                            let open = || inner.client.open_file_write(n, options);
+                            let result = match inner.handles.insert_write(ino, append, open).await {
+                                // Truncating has to go through the writer other handles share
+                                Ok((fh, file, true)) if truncate => {
+                                    match file.lock().await.set_len(0).await {
+                                        Ok(_) => Ok(fh),
+                                        Err(e) => {
+                                            let _ = Self::release_handle(&inner, fh).await;
+                                            Err(FsError::RuntimeError(e.to_string()))
+                                        }
+                                    }
+                                }
+                                Ok((fh, _, _)) => Ok(fh),
+                                Err(e) => Err(e),
+                            };
+                            match result {
+                                Ok(fh) => {
+                                    inner.usage.release(freed);
+                                    reply.opened(fh, 0)
+                                }
+                                Err(e) => {
+                                    error!("error opening file for writing: {:?}", e);
+                                    reply.error(e.errno())
+                                }
                             }
-                            info!(
-                                "create file: {}",
-                                n.name.as_ref().unwrap_or(&"".to_string())
-                            );
-                            let file = inner.client.open_file_write(n).await;
-                            let _already_open = inner.write_handles.lock().await.insert(ino, file);
-                            reply.opened(0, 0);
                         }
                         OpenMode::Read => {
-                            info!("read file: {}", n.name.as_ref().unwrap_or(&"".to_string()));
-                            if let Ok(file) = inner.client.open_file_read(n).await {
-                                inner.read_handles.lock().await.insert(ino, file);
-                                reply.opened(0, 0);
-                            } else {
-                                reply.error(EUNKNOWN);
+                            info!("read file: {:?}", n.file_name());
+                            match inner.client.open_file_read(n).await {
+                                Ok(file) => {
+                                    let fh = inner.handles.insert_read(file).await;
+                                    reply.opened(fh, 0);
+                                }
+                                Err(e) => {
+                                    error!("error opening file for reading: {:?}", e);
+                                    reply.error(e.errno())
+                                }
                             }
                         }
-                        OpenMode::ReadWrite => reply.error(EPERM),
                     },
                     None => reply.error(ENOENT),
                 },
-                Err(_) => reply.error(EUNKNOWN),
+                Err(e) => reply.error(e.errno()),
             }
         });
     }
@@ -270,28 +889,51 @@ impl Filesystem for DiscFs {
     fn write(
         &mut self,
         _req: &fuser::Request<'_>,
-        ino: u64,
+        _ino: u64,
         fh: u64,
         offset: i64,
         data: &[u8],
-        write_flags: u32,
-        flags: i32,
-        lock_owner: Option<u64>,
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
         reply: fuser::ReplyWrite,
     ) {
         let inner = self.inner.clone();
         let data = data.to_owned();
         self.rt.spawn(async move {
-            let mut handles = inner.write_handles.lock().await;
-            let file = handles.get_mut(&ino);
-            if let Some(handle) = file {
-                if let Ok(written) = handle.write(&data).await {
-                    reply.written(written as u32)
-                } else {
-                    reply.error(EUNKNOWN)
+            if let Some(FileHandle::Write { file, append, .. }) = inner.handles.get(fh).await {
+                let mut handle = file.lock().await;
+                // Appending handles write at the end wherever the kernel thinks it is
+                let position = match append {
+                    true => SeekFrom::End(0),
+                    false => SeekFrom::Start(offset as u64),
+                };
+                let size = handle.size();
+                let end = match append {
+                    true => size + data.len() as u64,
+                    false => offset as u64 + data.len() as u64,
+                };
+                if let Err(e) = inner.usage.reserve(end.saturating_sub(size)) {
+                    return reply.error(e.errno());
+                }
+                let claimed = max(size, end);
+                if handle.seek(position).await.is_err() {
+                    inner.usage.adjust(claimed, size);
+                    reply.error(EINVAL);
+                    return;
+                }
+                let written = handle.write(&data).await;
+                // A write that failed or fell short gives back what it didn't use
+                inner.usage.adjust(claimed, handle.size());
+                match written {
+                    Ok(written) => reply.written(written as u32),
+                    Err(e) => {
+                        error!("error writing file: {:?}", e);
+                        reply.error(io_errno(&e))
+                    }
                 };
             } else {
-                reply.error(EUNKNOWN);
+                reply.error(EBADF);
             }
         });
     }
@@ -299,17 +941,99 @@ impl Filesystem for DiscFs {
     fn getattr(&mut self, _req: &fuser::Request<'_>, ino: u64, reply: fuser::ReplyAttr) {
         let inner = self.inner.clone();
         self.rt.spawn(async move {
-            let result = inner.db.get_node_by_id(ino).await;
-            let node = result.unwrap_or(None);
-            if let Some(node) = node {
-                if let Ok(attrs) = attrs_from_node(&node) {
-                    reply.attr(&Duration::from_millis(64), &attrs);
-                } else {
-                    reply.error(EUNKNOWN)
+            match inner.db.get_node_by_id(ino).await {
+                Ok(Some(node)) => match Self::node_attrs(&inner, &node).await {
+                    Ok(attrs) => reply.attr(&Duration::from_millis(64), &attrs),
+                    Err(e) => reply.error(e.errno()),
+                },
+                Ok(None) => reply.error(ENOENT),
+                Err(e) => reply.error(e.errno()),
+            };
+        });
+    }
+
+    fn setattr(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        flags: Option<u32>,
+        reply: fuser::ReplyAttr,
+    ) {
+        debug!(
+            "setattr(ino: {:#x?}, mode: {:?}, uid: {:?}, \
+            gid: {:?}, size: {:?}, fh: {:?}, flags: {:?})",
+            ino, mode, uid, gid, size, fh, flags
+        );
+        let inner = self.inner.clone();
+        self.rt.spawn(async move {
+            let node = match inner.db.get_node_by_id(ino).await {
+                Ok(Some(node)) => node,
+                Ok(None) => {
+                    reply.error(ENOENT);
+                    return;
                 }
-            } else {
-                reply.error(ENOENT)
+                Err(e) => {
+                    reply.error(e.errno());
+                    return;
+                }
+            };
+            if let Some(size) = size {
+                if node.directory {
+                    reply.error(EISDIR);
+                    return;
+                }
+                let current = match Self::node_attrs(&inner, &node).await {
+                    Ok(attrs) => attrs.size,
+                    Err(e) => return reply.error(e.errno()),
+                };
+                if let Err(e) = inner.usage.reserve(size.saturating_sub(current)) {
+                    return reply.error(e.errno());
+                }
+                let result = Self::truncate(&inner, node, size).await;
+                let resized = if result.is_ok() { size } else { current };
+                inner.usage.adjust(max(size, current), resized);
+                if let Err(e) = result {
+                    error!("error truncating file: {:?}", e);
+                    reply.error(io_errno(&e));
+                    return;
+                }
+            }
+
+            let time = |time: TimeOrNow| match time {
+                TimeOrNow::SpecificTime(time) => time,
+                TimeOrNow::Now => SystemTime::now(),
+            };
+            let attributes = NodeAttributes {
+                mode: mode.map(|mode| mode & 0o7777),
+                uid,
+                gid,
+                atime: atime.map(time),
+                mtime: mtime.map(time),
             };
+            let result = inner.db.set_node_attributes(ino as i64, attributes).await;
+            let node = match result {
+                Ok(_) => inner.db.get_node_by_id(ino).await,
+                Err(e) => Err(e),
+            };
+            match node {
+                Ok(Some(node)) => match Self::node_attrs(&inner, &node).await {
+                    Ok(attrs) => reply.attr(&Duration::from_millis(64), &attrs),
+                    Err(e) => reply.error(e.errno()),
+                },
+                Ok(None) => reply.error(ENOENT),
+                Err(e) => reply.error(e.errno()),
+            }
         });
     }
 
@@ -317,38 +1041,151 @@ impl Filesystem for DiscFs {
         &mut self,
         _req: &fuser::Request<'_>,
         ino: u64,
-        _fh: u64,
-        flags: i32,
+        fh: u64,
+        _flags: i32,
         _lock_owner: Option<u64>,
         _flush: bool,
         reply: fuser::ReplyEmpty,
     ) {
         let inner = self.inner.clone();
         self.rt.spawn(async move {
-            if Self::is_write(flags) {
-                if let Some(handle) = inner.write_handles.lock().await.get_mut(&ino) {
-                    match handle.flush().await {
-                        Ok(_) => {
-                            handle.finish();
-                            reply.ok()
-                        }
-                        Err(_) => reply.error(EUNKNOWN),
-                    }
-                    inner.write_handles.lock().await.remove(&ino);
-                } else {
-                    reply.error(ENOENT)
+            // Errors were already reported by flush when the file was closed,
+            // anything still failing now is lost
+            match Self::release_handle(&inner, fh).await {
+                Ok(_) => reply.ok(),
+                Err(e) => {
+                    error!("discarding unsaved changes to {:#x?}: {:?}", ino, e);
+                    reply.error(io_errno(&e))
                 }
-            } else {
-                if let Some(handle) = inner.read_handles.lock().await.remove(&ino) {
-                    handle.finish();
-                    reply.ok();
-                } else {
-                    reply.error(ENOENT);
+            }
+        });
+    }
+
+    fn flush(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        ino: u64,
+        fh: u64,
+        lock_owner: u64,
+        reply: fuser::ReplyEmpty,
+    ) {
+        let inner = self.inner.clone();
+        self.rt.spawn(async move {
+            // Closing any descriptor drops the POSIX locks its process holds on the file
+            // and gives up its waits for more
+            inner.locks.unlock_owner(ino, lock_owner);
+            match Self::flush_handle(&inner, fh).await {
+                Ok(_) => reply.ok(),
+                Err(e) => {
+                    error!("error flushing file: {:?}", e);
+                    reply.error(io_errno(&e))
                 }
             }
         });
     }
 
+    fn fsync(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _datasync: bool,
+        reply: fuser::ReplyEmpty,
+    ) {
+        let inner = self.inner.clone();
+        self.rt.spawn(async move {
+            match Self::sync(&inner, fh).await {
+                Ok(_) => reply.ok(),
+                Err(e) => {
+                    error!("error syncing file: {:?}", e);
+                    reply.error(io_errno(&e))
+                }
+            }
+        });
+    }
+
+    fn getlk(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        ino: u64,
+        _fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        reply: fuser::ReplyLock,
+    ) {
+        let Some(kind) = Self::lock_kind(typ) else {
+            return reply.error(EINVAL);
+        };
+        let lock = Lock {
+            owner: lock_owner,
+            pid,
+            start,
+            end,
+            kind,
+        };
+        match self.inner.locks.conflict(ino, &lock) {
+            Some(held) => {
+                let typ = match held.kind {
+                    LockKind::Read => libc::F_RDLCK,
+                    LockKind::Write => libc::F_WRLCK,
+                };
+                reply.locked(held.start, held.end, typ, held.pid)
+            }
+            None => reply.locked(start, end, libc::F_UNLCK, 0),
+        }
+    }
+
+    fn setlk(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        ino: u64,
+        _fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        sleep: bool,
+        reply: fuser::ReplyEmpty,
+    ) {
+        debug!(
+            "setlk(ino: {:#x?}, lock_owner: {:#x?}, start: {}, end: {}, typ: {}, sleep: {})",
+            ino, lock_owner, start, end, typ, sleep
+        );
+        if typ == libc::F_UNLCK {
+            self.inner.locks.unlock(ino, lock_owner, start, end);
+            return reply.ok();
+        }
+        let Some(kind) = Self::lock_kind(typ) else {
+            return reply.error(EINVAL);
+        };
+        let lock = Lock {
+            owner: lock_owner,
+            pid,
+            start,
+            end,
+            kind,
+        };
+        if !sleep {
+            match self.inner.locks.try_lock(ino, lock) {
+                Ok(_) => reply.ok(),
+                Err(_) => reply.error(EAGAIN),
+            }
+            return;
+        }
+        let inner = self.inner.clone();
+        self.rt.spawn(async move {
+            if inner.locks.lock(ino, lock).await {
+                reply.ok()
+            } else {
+                reply.error(EINTR)
+            }
+        });
+    }
+
     fn readdir(
         &mut self,
         _req: &fuser::Request<'_>,
@@ -360,38 +1197,64 @@ impl Filesystem for DiscFs {
         debug!("readdir ino: {:?} offset: {:?}", ino, offset);
         let inner = self.inner.clone();
         self.rt.spawn(async move {
-            let result = inner.db.get_nodes_by_parent(ino as i64).await;
-            if let Ok(nodes) = result {
-                debug!("files {:?}", &nodes);
-                let mut full = false;
-                let mut i = offset as usize;
-                while !full && i < nodes.len() {
-                    let node = &nodes[i];
-                    full = reply.add(
-                        node.id as u64,
-                        (i + 1) as i64,
-                        if node.directory {
-                            FileType::Directory
-                        } else {
-                            FileType::RegularFile
-                        },
-                        node.name.clone().unwrap_or_else(|| "".to_string()),
-                    );
-                    i += 1;
+            match Self::dir_entries(&inner, ino, offset).await {
+                Ok(entries) => {
+                    for (offset, name, node) in entries {
+                        if reply.add(node.id as u64, offset, kind_from_node(&node), name) {
+                            break;
+                        }
+                    }
+                    reply.ok();
                 }
-                reply.ok();
-            } else {
-                reply.error(EUNKNOWN);
+                Err(e) => reply.error(e),
             }
         });
     }
 
-    fn read(
+    fn readdirplus(
         &mut self,
         _req: &fuser::Request<'_>,
         ino: u64,
         _fh: u64,
-        _offset: i64,
+        offset: i64,
+        mut reply: fuser::ReplyDirectoryPlus,
+    ) {
+        debug!("readdirplus ino: {:?} offset: {:?}", ino, offset);
+        let inner = self.inner.clone();
+        self.rt.spawn(async move {
+            let entries = match Self::dir_entries(&inner, ino, offset).await {
+                Ok(entries) => entries,
+                Err(e) => return reply.error(e),
+            };
+            for (offset, name, node) in entries {
+                let attrs = match Self::node_attrs(&inner, &node).await {
+                    Ok(attrs) => attrs,
+                    Err(e) => {
+                        error!("error in readdirplus: {:?}", e);
+                        return reply.error(e.errno());
+                    }
+                };
+                if reply.add(
+                    node.id as u64,
+                    offset,
+                    name,
+                    &Duration::from_millis(64),
+                    &attrs,
+                    0,
+                ) {
+                    break;
+                }
+            }
+            reply.ok();
+        });
+    }
+
+    fn read(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        _ino: u64,
+        fh: u64,
+        offset: i64,
         size: u32,
         _flags: i32,
         _lock_owner: Option<u64>,
@@ -399,17 +1262,30 @@ impl Filesystem for DiscFs {
     ) {
         let inner = self.inner.clone();
         self.rt.spawn(async move {
-            if let Some(handle) = inner.read_handles.lock().await.get_mut(&ino) {
-                let mut buffer = vec![0; size as usize].into_boxed_slice();
-                let result = handle.read(&mut buffer).await;
-                if let Ok(written) = result {
+            let mut buffer = vec![0; size as usize].into_boxed_slice();
+            let position = SeekFrom::Start(offset as u64);
+            // Files opened for reading and writing read back through the writer
+            let result = match inner.handles.get(fh).await {
+                Some(FileHandle::Read(file)) => {
+                    Self::read_handle(file.lock().await.as_mut(), position, &mut buffer).await
+                }
+                Some(FileHandle::Write { file, .. }) => {
+                    Self::read_handle(file.lock().await.as_mut(), position, &mut buffer).await
+                }
+                None => {
+                    reply.error(EBADF);
+                    return;
+                }
+            };
+            match result {
+                Ok(written) => {
                     trace!("written: {:?}", written);
                     reply.data(&buffer[..written]);
-                } else {
-                    reply.error(EUNKNOWN);
                 }
-            } else {
-                reply.error(ENOENT);
+                Err(e) => {
+                    error!("error reading file: {:?}", e);
+                    reply.error(io_errno(&e));
+                }
             }
         });
     }
@@ -424,19 +1300,14 @@ impl Filesystem for DiscFs {
         let inner = self.inner.clone();
         let name = name.to_owned();
         self.rt.spawn(async move {
-            let result = inner
-                .db
-                .delete_node(parent as i64, &name.to_string_lossy(), true)
-                .await;
-            if let Ok(deleted) = result {
-                if deleted == 0 {
-                    reply.error(ENOENT);
-                } else {
+            let result = inner.db.delete_node(parent as i64, &name, true).await;
+            match result {
+                Ok(None) => reply.error(ENOENT),
+                Ok(Some(_)) => {
                     info!("deleted directory: {:?}", name);
                     reply.ok();
                 }
-            } else {
-                reply.error(EUNKNOWN);
+                Err(e) => reply.error(e.errno()),
             }
         });
     }
@@ -451,19 +1322,20 @@ impl Filesystem for DiscFs {
         let inner = self.inner.clone();
         let name = name.to_owned();
         self.rt.spawn(async move {
-            let result = inner
-                .db
-                .delete_node(parent as i64, &name.to_string_lossy(), false)
-                .await;
-            if let Ok(deleted) = result {
-                if deleted == 0 {
-                    reply.error(ENOENT);
-                } else {
+            let mut removals = inner.removals.lock().await;
+            let removed = Self::sized_node(&inner, parent, &name).await;
+            let result = inner.db.delete_node(parent as i64, &name, false).await;
+            match result {
+                Ok(None) => reply.error(ENOENT),
+                Ok(Some(chunks)) => {
+                    let unused =
+                        Self::release_removed(&inner, &mut removals, removed, chunks).await;
+                    drop(removals);
                     info!("deleted file: {:?}", name);
                     reply.ok();
+                    Self::discard(&inner, &unused).await;
                 }
-            } else {
-                reply.error(EUNKNOWN);
+                Err(e) => reply.error(e.errno()),
             }
         });
     }
@@ -475,27 +1347,39 @@ impl Filesystem for DiscFs {
         name: &std::ffi::OsStr,
         newparent: u64,
         newname: &std::ffi::OsStr,
-        _flags: u32,
+        flags: u32,
         reply: fuser::ReplyEmpty,
     ) {
+        let mode = match flags {
+            0 => RenameMode::Replace,
+            RENAME_NOREPLACE => RenameMode::NoReplace,
+            RENAME_EXCHANGE => RenameMode::Exchange,
+            _ => return reply.error(EINVAL),
+        };
         let inner = self.inner.clone();
         let name = name.to_owned();
         let newparent = newparent.to_owned();
         let newname = newname.to_owned();
         self.rt.spawn(async move {
+            let mut removals = inner.removals.lock().await;
+            // The target loses its name when it gets replaced
+            let replaced = match mode {
+                RenameMode::Exchange => None,
+                _ => Self::sized_node(&inner, newparent, &newname).await,
+            };
             let result = inner
                 .db
-                .move_node(
-                    parent as i64,
-                    &name.to_string_lossy(),
-                    newparent as i64,
-                    &newname.to_string_lossy(),
-                )
+                .move_node(parent as i64, &name, newparent as i64, &newname, mode)
                 .await;
             match result {
-                Ok(_) => reply.ok(),
-                Err(DbError::DoesNotExist(_)) => reply.error(ENOENT),
-                Err(_) => reply.error(EUNKNOWN),
+                Ok(chunks) => {
+                    let unused =
+                        Self::release_removed(&inner, &mut removals, replaced, chunks).await;
+                    drop(removals);
+                    reply.ok();
+                    Self::discard(&inner, &unused).await;
+                }
+                Err(e) => reply.error(e.errno()),
             }
         });
     }
@@ -0,0 +1,273 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use tokio::sync::{Mutex, OnceCell};
+
+use crate::client::client::{CloudRead, CloudWrite};
+
+use super::error::FsError;
+
+pub type SharedRead = Arc<Mutex<Box<dyn CloudRead>>>;
+pub type SharedWrite = Arc<Mutex<Box<dyn CloudWrite>>>;
+
+/// State behind a file handle returned from open or create
+#[derive(Clone)]
+pub enum FileHandle {
+    /// Reads the content committed when the file was opened, with its own cursor.
+    /// Changes committed by a writer afterwards only show up for later opens
+    Read(SharedRead),
+    /// Shares the single writer of the inode with every other handle that can write to it
+    Write {
+        ino: u64,
+        file: SharedWrite,
+        append: bool,
+    },
+}
+
+struct OpenWriter {
+    /// Set once the first open of the inode finishes, which other opens wait for
+    file: Arc<OnceCell<SharedWrite>>,
+    handles: usize,
+}
+
+/// Open files by handle, with one writer per inode no matter how many handles use it
+pub struct HandleTable {
+    next_fh: AtomicU64,
+    handles: Mutex<HashMap<u64, FileHandle>>,
+    writers: Mutex<HashMap<u64, OpenWriter>>,
+}
+
+impl HandleTable {
+    pub fn new() -> Self {
+        Self {
+            // 0 is left out so it never looks like an unset handle
+            next_fh: AtomicU64::new(1),
+            handles: Mutex::new(HashMap::new()),
+            writers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn insert_read(&self, file: Box<dyn CloudRead>) -> u64 {
+        self.insert(FileHandle::Read(Arc::new(Mutex::new(file))))
+            .await
+    }
+
+    /// Adds a handle writing to `ino`, calling `open` only if the inode has no writer yet.
+    /// Returns the handle and whether the writer was already open
+    pub async fn insert_write<F, Fut>(
+        &self,
+        ino: u64,
+        append: bool,
+        open: F,
+    ) -> Result<(u64, SharedWrite, bool), FsError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Box<dyn CloudWrite>, FsError>>,
+    {
+        // The table is only locked to claim the inode, opening it can take a while
+        let cell = {
+            let mut writers = self.writers.lock().await;
+            let writer = writers.entry(ino).or_insert_with(|| OpenWriter {
+                file: Arc::new(OnceCell::new()),
+                handles: 0,
+            });
+            writer.handles += 1;
+            writer.file.clone()
+        };
+        let mut existing = true;
+        let opened = cell
+            .get_or_try_init(|| {
+                existing = false;
+                async { Ok(Arc::new(Mutex::new(open().await?))) }
+            })
+            .await;
+        let file = match opened {
+            Ok(file) => file.clone(),
+            Err(e) => {
+                let mut writers = self.writers.lock().await;
+                if let Some(writer) = writers.get_mut(&ino) {
+                    writer.handles -= 1;
+                    if writer.handles == 0 {
+                        writers.remove(&ino);
+                    }
+                }
+                return Err(e);
+            }
+        };
+        let handle = FileHandle::Write {
+            ino,
+            file: file.clone(),
+            append,
+        };
+        Ok((self.insert(handle).await, file, existing))
+    }
+
+    async fn insert(&self, handle: FileHandle) -> u64 {
+        let fh = self.next_fh.fetch_add(1, Ordering::Relaxed);
+        self.handles.lock().await.insert(fh, handle);
+        fh
+    }
+
+    pub async fn get(&self, fh: u64) -> Option<FileHandle> {
+        self.handles.lock().await.get(&fh).cloned()
+    }
+
+    /// Writer of an inode if any handle has it open
+    pub async fn writer(&self, ino: u64) -> Option<SharedWrite> {
+        self.writers
+            .lock()
+            .await
+            .get(&ino)
+            .and_then(|writer| writer.file.get().cloned())
+    }
+
+    /// Drops a handle. The writer is returned once its last handle is gone so it can be committed
+    pub async fn remove(&self, fh: u64) -> Option<(FileHandle, Option<SharedWrite>)> {
+        let handle = self.handles.lock().await.remove(&fh)?;
+        let mut last_writer = None;
+        if let FileHandle::Write { ino, .. } = &handle {
+            let mut writers = self.writers.lock().await;
+            if let Some(writer) = writers.get_mut(ino) {
+                writer.handles -= 1;
+                if writer.handles == 0 {
+                    last_writer = writers
+                        .remove(ino)
+                        .and_then(|writer| writer.file.get().cloned());
+                }
+            }
+        }
+        Some((handle, last_writer))
+    }
+}
+
+impl Default for HandleTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::SeekFrom;
+
+    use async_trait::async_trait;
+
+    use crate::util::async_file::{AsyncRead, AsyncSeek, AsyncWrite};
+
+    use super::*;
+
+    /// Writer that only tracks its size
+    struct SizeWrite(u64);
+
+    #[async_trait]
+    impl AsyncWrite for SizeWrite {
+        async fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0 += buf.len() as u64;
+            Ok(buf.len())
+        }
+
+        async fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[async_trait]
+    impl AsyncRead for SizeWrite {
+        async fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+            Ok(0)
+        }
+    }
+
+    #[async_trait]
+    impl AsyncSeek for SizeWrite {
+        async fn seek(&mut self, _pos: SeekFrom) -> std::io::Result<u64> {
+            Ok(0)
+        }
+    }
+
+    #[async_trait]
+    impl CloudWrite for SizeWrite {
+        fn size(&self) -> u64 {
+            self.0
+        }
+
+        async fn set_len(&mut self, size: u64) -> std::io::Result<()> {
+            self.0 = size;
+            Ok(())
+        }
+
+        fn finish(&self) {}
+    }
+
+    async fn open_write() -> Result<Box<dyn CloudWrite>, FsError> {
+        Ok(Box::new(SizeWrite(0)))
+    }
+
+    #[tokio::test]
+    async fn test_shared_writer() -> Result<(), FsError> {
+        let table = HandleTable::new();
+        let (first, file, existing) = table.insert_write(7, false, open_write).await?;
+        assert!(!existing);
+        file.lock().await.write(b"abc").await.unwrap();
+
+        // A second open writes through the same writer under its own handle
+        let (second, file, existing) = table.insert_write(7, true, open_write).await?;
+        assert!(existing);
+        assert_ne!(first, second);
+        assert_eq!(file.lock().await.size(), 3);
+
+        let (_, last) = table.remove(first).await.unwrap();
+        assert!(last.is_none());
+        assert!(table.writer(7).await.is_some());
+        let (handle, last) = table.remove(second).await.unwrap();
+        assert!(matches!(handle, FileHandle::Write { append: true, .. }));
+        assert_eq!(last.unwrap().lock().await.size(), 3);
+        assert!(table.writer(7).await.is_none());
+        assert!(table.remove(second).await.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_slow_open() -> Result<(), FsError> {
+        let table = Arc::new(HandleTable::new());
+        let (release, opened) = tokio::sync::oneshot::channel::<()>();
+        let slow = {
+            let table = table.clone();
+            tokio::spawn(async move {
+                let open = || async {
+                    let _ = opened.await;
+                    open_write().await
+                };
+                table.insert_write(7, false, open).await
+            })
+        };
+        tokio::task::yield_now().await;
+
+        // Other inodes don't wait for it, while a second open of the same one shares its writer
+        table.insert_write(8, false, open_write).await?;
+        let second = {
+            let table = table.clone();
+            tokio::spawn(async move { table.insert_write(7, false, open_write).await })
+        };
+        release.send(()).unwrap();
+        let (_, first, existing) = slow.await.unwrap()?;
+        assert!(!existing);
+        let (_, shared, existing) = second.await.unwrap()?;
+        assert!(existing);
+        assert!(Arc::ptr_eq(&first, &shared));
+
+        // A failed open leaves nothing behind for the next one
+        let failed = || async { Err(FsError::RuntimeError("open failed".to_string())) };
+        assert!(table.insert_write(9, false, failed).await.is_err());
+        assert!(table.writers.lock().await.get(&9).is_none());
+        let (_, _, existing) = table.insert_write(9, false, open_write).await?;
+        assert!(!existing);
+        Ok(())
+    }
+}
@@ -0,0 +1,310 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use tokio::sync::Notify;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockKind {
+    Read,
+    Write,
+}
+
+/// Advisory POSIX lock on the inclusive byte range `start..=end`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lock {
+    pub owner: u64,
+    pub pid: u32,
+    pub start: u64,
+    pub end: u64,
+    pub kind: LockKind,
+}
+
+impl Lock {
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start <= end && start <= self.end
+    }
+
+    fn conflicts(&self, other: &Lock) -> bool {
+        self.owner != other.owner
+            && self.overlaps(other.start, other.end)
+            && (self.kind == LockKind::Write || other.kind == LockKind::Write)
+    }
+}
+
+#[derive(Default)]
+struct Locks {
+    held: HashMap<u64, Vec<Lock>>,
+    /// Blocked requests of each owner on each inode
+    waiting: HashMap<(u64, u64), Waiting>,
+}
+
+#[derive(Default)]
+struct Waiting {
+    count: usize,
+    /// Bumped to cancel the requests waiting when it is read
+    epoch: u64,
+}
+
+/// Locks held on each inode, only known to this mount
+#[derive(Default)]
+pub struct LockTable {
+    locks: Mutex<Locks>,
+    /// Woken whenever locks are released so blocked requests can try again
+    released: Notify,
+}
+
+/// Counts a blocked request until it is done waiting, however it stops
+struct WaitGuard<'a> {
+    table: &'a LockTable,
+    key: (u64, u64),
+}
+
+impl Drop for WaitGuard<'_> {
+    fn drop(&mut self) {
+        let mut locks = self.table.locks.lock().unwrap();
+        if let Some(waiting) = locks.waiting.get_mut(&self.key) {
+            waiting.count -= 1;
+            if waiting.count == 0 {
+                locks.waiting.remove(&self.key);
+            }
+        }
+    }
+}
+
+impl LockTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// First lock held by another owner that keeps `lock` from being taken
+    pub fn conflict(&self, ino: u64, lock: &Lock) -> Option<Lock> {
+        let locks = self.locks.lock().unwrap();
+        locks
+            .held
+            .get(&ino)?
+            .iter()
+            .find(|held| held.conflicts(lock))
+            .copied()
+    }
+
+    /// Takes the lock, replacing whatever the owner held on the range before
+    pub fn try_lock(&self, ino: u64, lock: Lock) -> Result<(), Lock> {
+        let mut locks = self.locks.lock().unwrap();
+        Self::take(&mut locks, ino, lock)?;
+        drop(locks);
+        // Downgrading from a write lock can let readers in
+        self.released.notify_waiters();
+        Ok(())
+    }
+
+    fn take(locks: &mut Locks, ino: u64, lock: Lock) -> Result<(), Lock> {
+        let held = locks.held.entry(ino).or_default();
+        if let Some(conflict) = held.iter().find(|held| held.conflicts(&lock)) {
+            // Whoever holds it keeps the entry alive
+            return Err(*conflict);
+        }
+        Self::remove_range(held, lock.owner, lock.start, lock.end);
+        held.push(lock);
+        Ok(())
+    }
+
+    /// Waits until no other owner holds a conflicting lock.
+    /// Returns false without taking it if the owner closed the file in the meantime,
+    /// which is all we hear of a waiting process that was interrupted or killed
+    pub async fn lock(&self, ino: u64, lock: Lock) -> bool {
+        let key = (ino, lock.owner);
+        let epoch = {
+            let mut locks = self.locks.lock().unwrap();
+            let waiting = locks.waiting.entry(key).or_default();
+            waiting.count += 1;
+            waiting.epoch
+        };
+        let _guard = WaitGuard { table: self, key };
+        loop {
+            // Registered before trying so a release in between isn't missed
+            let released = self.released.notified();
+            {
+                let mut locks = self.locks.lock().unwrap();
+                if locks.waiting.get(&key).map(|w| w.epoch) != Some(epoch) {
+                    return false;
+                }
+                if Self::take(&mut locks, ino, lock).is_ok() {
+                    drop(locks);
+                    self.released.notify_waiters();
+                    return true;
+                }
+            }
+            released.await;
+        }
+    }
+
+    pub fn unlock(&self, ino: u64, owner: u64, start: u64, end: u64) {
+        let mut locks = self.locks.lock().unwrap();
+        Self::release(&mut locks, ino, owner, start, end);
+        drop(locks);
+        self.released.notify_waiters();
+    }
+
+    fn release(locks: &mut Locks, ino: u64, owner: u64, start: u64, end: u64) {
+        if let Some(held) = locks.held.get_mut(&ino) {
+            Self::remove_range(held, owner, start, end);
+            if held.is_empty() {
+                locks.held.remove(&ino);
+            }
+        }
+    }
+
+    /// Drops every lock the owner holds on the inode, as happens when it closes the file,
+    /// and cancels the requests it still has waiting for one
+    pub fn unlock_owner(&self, ino: u64, owner: u64) {
+        let mut locks = self.locks.lock().unwrap();
+        Self::release(&mut locks, ino, owner, 0, u64::MAX);
+        if let Some(waiting) = locks.waiting.get_mut(&(ino, owner)) {
+            waiting.epoch += 1;
+        }
+        drop(locks);
+        self.released.notify_waiters();
+    }
+
+    /// Cuts a range out of the owner's locks, splitting any that extend past it
+    fn remove_range(held: &mut Vec<Lock>, owner: u64, start: u64, end: u64) {
+        let mut kept = Vec::with_capacity(held.len());
+        for lock in held.drain(..) {
+            if lock.owner != owner || !lock.overlaps(start, end) {
+                kept.push(lock);
+                continue;
+            }
+            if lock.start < start {
+                kept.push(Lock {
+                    end: start - 1,
+                    ..lock
+                });
+            }
+            if lock.end > end {
+                kept.push(Lock {
+                    start: end + 1,
+                    ..lock
+                });
+            }
+        }
+        *held = kept;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::Arc, time::Duration};
+
+    use super::*;
+
+    fn lock(owner: u64, start: u64, end: u64, kind: LockKind) -> Lock {
+        Lock {
+            owner,
+            pid: owner as u32,
+            start,
+            end,
+            kind,
+        }
+    }
+
+    #[test]
+    fn test_conflicts() {
+        let table = LockTable::new();
+        table.try_lock(1, lock(1, 0, 99, LockKind::Read)).unwrap();
+        // Readers share, writers don't, and other inodes are separate
+        table.try_lock(1, lock(2, 50, 149, LockKind::Read)).unwrap();
+        assert_eq!(
+            table.try_lock(1, lock(3, 90, 90, LockKind::Write)),
+            Err(lock(1, 0, 99, LockKind::Read))
+        );
+        table
+            .try_lock(1, lock(3, 150, 200, LockKind::Write))
+            .unwrap();
+        table.try_lock(2, lock(3, 0, 99, LockKind::Write)).unwrap();
+
+        // An owner never conflicts with itself and can upgrade
+        table.try_lock(1, lock(1, 0, 49, LockKind::Write)).unwrap();
+        assert!(table
+            .conflict(1, &lock(2, 10, 10, LockKind::Read))
+            .is_some());
+        assert!(table
+            .conflict(1, &lock(2, 60, 60, LockKind::Read))
+            .is_none());
+    }
+
+    #[test]
+    fn test_unlock_splits() {
+        let table = LockTable::new();
+        table.try_lock(1, lock(1, 0, 99, LockKind::Write)).unwrap();
+        table.unlock(1, 1, 40, 59);
+        assert!(table
+            .conflict(1, &lock(2, 40, 59, LockKind::Write))
+            .is_none());
+        assert_eq!(
+            table.conflict(1, &lock(2, 60, 60, LockKind::Read)),
+            Some(lock(1, 60, 99, LockKind::Write))
+        );
+
+        table.unlock_owner(1, 1);
+        assert!(table
+            .conflict(1, &lock(2, 0, u64::MAX, LockKind::Write))
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_blocking_lock() {
+        let table = Arc::new(LockTable::new());
+        table
+            .try_lock(1, lock(1, 0, u64::MAX, LockKind::Write))
+            .unwrap();
+
+        let waiter = tokio::spawn({
+            let table = table.clone();
+            async move { table.lock(1, lock(2, 0, 10, LockKind::Read)).await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!waiter.is_finished());
+
+        table.unlock_owner(1, 1);
+        assert!(tokio::time::timeout(Duration::from_secs(1), waiter)
+            .await
+            .unwrap()
+            .unwrap());
+        assert!(table.conflict(1, &lock(1, 5, 5, LockKind::Write)).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_cancel_wait() {
+        let table = Arc::new(LockTable::new());
+        table
+            .try_lock(1, lock(1, 0, u64::MAX, LockKind::Write))
+            .unwrap();
+
+        let waiter = tokio::spawn({
+            let table = table.clone();
+            async move { table.lock(1, lock(2, 0, 10, LockKind::Write)).await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        // Closing the file gives up the wait instead of leaving the lock to a gone process
+        table.unlock_owner(1, 2);
+        assert!(!tokio::time::timeout(Duration::from_secs(1), waiter)
+            .await
+            .unwrap()
+            .unwrap());
+        table.unlock_owner(1, 1);
+        assert!(table
+            .conflict(1, &lock(3, 0, 10, LockKind::Write))
+            .is_none());
+        assert!(table.locks.lock().unwrap().waiting.is_empty());
+
+        // Later requests of the same owner wait as usual
+        table.try_lock(1, lock(1, 0, 10, LockKind::Write)).unwrap();
+        let waiter = tokio::spawn({
+            let table = table.clone();
+            async move { table.lock(1, lock(2, 0, 10, LockKind::Write)).await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        table.unlock(1, 1, 0, 10);
+        assert!(waiter.await.unwrap());
+    }
+}
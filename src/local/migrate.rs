@@ -0,0 +1,299 @@
+use log::info;
+use sqlx::{Connection, Pool, Sqlite};
+
+use super::error::DbError;
+
+/// Schema change that takes the database from the version before it to `version`
+pub struct Migration {
+    pub version: i64,
+    pub description: &'static str,
+    pub sql: &'static str,
+    /// Recreates tables, which needs foreign keys off or dropping the old table cascades
+    pub rebuild: bool,
+}
+
+macro_rules! migration {
+    ($version:literal, $file:literal, $description:literal) => {
+        migration!($version, $file, $description, false)
+    };
+    ($version:literal, $file:literal, $description:literal, $rebuild:literal) => {
+        Migration {
+            version: $version,
+            description: $description,
+            sql: include_str!(concat!("migrations/", $file)),
+            rebuild: $rebuild,
+        }
+    };
+}
+
+/// Every schema change in order. New changes are only ever appended
+pub const MIGRATIONS: &[Migration] = &[
+    migration!(1, "0001_create_schema.sql", "creating node table"),
+    migration!(2, "0002_create_chunk.sql", "adding chunk table"),
+    migration!(
+        3,
+        "0003_add_node_attributes.sql",
+        "adding node attribute columns"
+    ),
+    migration!(
+        4,
+        "0004_add_symlink_target.sql",
+        "adding symlink target column"
+    ),
+    migration!(
+        5,
+        "0005_add_dirent.sql",
+        "splitting directory entries from nodes",
+        true
+    ),
+    migration!(6, "0006_add_xattr.sql", "adding extended attribute table"),
+    migration!(7, "0007_add_entry_dirent.sql", "adding directory entry ids"),
+    migration!(
+        8,
+        "0008_add_dirent_unique.sql",
+        "adding unique directory entry names"
+    ),
+    migration!(
+        9,
+        "0009_dirent_name_blob.sql",
+        "storing names as raw bytes",
+        true
+    ),
+    migration!(
+        10,
+        "0010_node_autoincrement.sql",
+        "never reusing node ids",
+        true
+    ),
+];
+
+/// Version of the newest schema this build knows
+pub fn latest_version() -> i64 {
+    MIGRATIONS.last().map_or(0, |migration| migration.version)
+}
+
+/// Brings the database at `path` up to the latest schema, backing it up first if it has any data.
+/// Databases written by a newer build are refused rather than risk corrupting them
+pub async fn migrate(connection: &Pool<Sqlite>, path: &str) -> Result<(), DbError> {
+    let version = schema_version(connection).await?;
+    if version > latest_version() {
+        return Err(DbError::SchemaTooNew(version));
+    }
+    if version == latest_version() {
+        return Ok(());
+    }
+    if version > 0 && path != ":memory:" {
+        backup(connection, &format!("{}.v{}.bak", path, version)).await?;
+    }
+    track_version(connection, version).await?;
+    for migration in MIGRATIONS.iter().filter(|m| m.version > version) {
+        info!(
+            "migrating database to version {}: {}",
+            migration.version, migration.description
+        );
+        apply(connection, migration).await?;
+    }
+    Ok(())
+}
+
+/// Current version, without writing anything to the database
+pub async fn schema_version(connection: &Pool<Sqlite>) -> Result<i64, DbError> {
+    if has_table(connection, "schema_version").await? {
+        let version: Option<i64> = sqlx::query_scalar("select version from schema_version")
+            .fetch_optional(connection)
+            .await?;
+        if let Some(version) = version {
+            return Ok(version);
+        }
+    }
+    legacy_version(connection).await
+}
+
+/// Version of a database from before versions were tracked, which only ever had the first schema
+async fn legacy_version(connection: &Pool<Sqlite>) -> Result<i64, DbError> {
+    Ok(if has_table(connection, "node").await? {
+        1
+    } else {
+        0
+    })
+}
+
+/// Starts the version table at `version` for databases created before it existed
+async fn track_version(connection: &Pool<Sqlite>, version: i64) -> Result<(), DbError> {
+    let mut transaction = connection.begin().await?;
+    sqlx::query("create table if not exists schema_version (version integer not null)")
+        .execute(&mut *transaction)
+        .await?;
+    sqlx::query(
+        "insert into schema_version (version) select ? where not exists (select 1 from schema_version)",
+    )
+    .bind(version)
+    .execute(&mut *transaction)
+    .await?;
+    transaction.commit().await?;
+    Ok(())
+}
+
+/// Runs a migration and records its version in one transaction
+async fn apply(connection: &Pool<Sqlite>, migration: &Migration) -> Result<(), DbError> {
+    let mut connection = connection.acquire().await?;
+    if migration.rebuild {
+        sqlx::query("pragma foreign_keys=off")
+            .execute(&mut *connection)
+            .await?;
+    }
+    let result = async {
+        let mut transaction = connection.begin().await?;
+        sqlx::query(migration.sql)
+            .execute(&mut *transaction)
+            .await?;
+        sqlx::query("update schema_version set version=?")
+            .bind(migration.version)
+            .execute(&mut *transaction)
+            .await?;
+        transaction.commit().await
+    }
+    .await;
+    if migration.rebuild {
+        sqlx::query("pragma foreign_keys=on")
+            .execute(&mut *connection)
+            .await?;
+    }
+    Ok(result?)
+}
+
+/// Writes a consistent copy of the database, replacing a backup left by an earlier failed attempt
+async fn backup(connection: &Pool<Sqlite>, backup_path: &str) -> Result<(), DbError> {
+    info!("backing up database to {}", backup_path);
+    match std::fs::remove_file(backup_path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(DbError::Other(e.to_string())),
+    }
+    sqlx::query("vacuum into ?")
+        .bind(backup_path)
+        .execute(connection)
+        .await?;
+    Ok(())
+}
+
+async fn has_table(connection: &Pool<Sqlite>, table: &str) -> Result<bool, DbError> {
+    let found: Option<String> =
+        sqlx::query_scalar("select name from sqlite_master where type='table' and name=?")
+            .bind(table)
+            .fetch_optional(connection)
+            .await?;
+    Ok(found.is_some())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{error::Error, ffi::OsStr, os::unix::ffi::OsStrExt, str::FromStr};
+
+    use sqlx::{sqlite::SqliteConnectOptions, SqlitePool};
+
+    use crate::{
+        local::db::{FsDatabase, RenameMode},
+        util::test::TempDir,
+    };
+
+    use super::*;
+
+    type TestResult = Result<(), Box<dyn Error>>;
+
+    /// Path of a database in `dir`
+    fn db_path(dir: &TempDir) -> String {
+        dir.0.join("fs.db").to_string_lossy().to_string()
+    }
+
+    async fn pool(path: &str) -> Result<Pool<Sqlite>, sqlx::Error> {
+        let options =
+            SqliteConnectOptions::from_str(&format!("sqlite:{}", path))?.create_if_missing(true);
+        SqlitePool::connect_with(options).await
+    }
+
+    #[test]
+    fn test_migrations_in_order() {
+        for (i, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version, i as i64 + 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_new_database() -> TestResult {
+        let db = FsDatabase::new(":memory:").await?;
+        assert_eq!(schema_version(&db.connection).await?, latest_version());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_legacy_upgrade() -> TestResult {
+        let dir = TempDir::new("legacy");
+        let path = db_path(&dir);
+        {
+            // Written by a build from before versions were tracked
+            let old = pool(&path).await?;
+            sqlx::query(MIGRATIONS[0].sql).execute(&old).await?;
+            sqlx::query(
+                "insert into node (id, name, parent, directory) values (2, 'file', 1, false), (3, 'file', 1, false)",
+            )
+            .execute(&old)
+            .await?;
+            old.close().await;
+        }
+
+        let db = FsDatabase::new(&path).await?;
+        assert_eq!(schema_version(&db.connection).await?, latest_version());
+        assert!(std::path::Path::new(&format!("{}.v1.bak", path)).exists());
+
+        // The backup is the database exactly as the old build left it
+        let backup = SqlitePool::connect(&format!("sqlite:{}.v1.bak", path)).await?;
+        assert!(!has_table(&backup, "schema_version").await?);
+        assert_eq!(schema_version(&backup).await?, 1);
+        backup.close().await;
+
+        // Duplicate names from before the constraint are renamed, and text names become bytes
+        assert_eq!(db.get_node(1, OsStr::new("file")).await?.unwrap().id, 2);
+        let types: Vec<String> = sqlx::query_scalar("select distinct typeof(name) from dirent")
+            .fetch_all(&db.connection)
+            .await?;
+        assert_eq!(types, ["blob"]);
+        let renamed = db.get_nodes_by_parent(1, 0, 10).await?;
+        assert_eq!(renamed.len(), 2);
+        assert_eq!(renamed[1].id, 3);
+        let name = renamed[1].file_name().to_owned();
+        assert!(name.as_bytes().starts_with(b"file ("));
+        assert!(matches!(
+            db.move_node(1, &name, 1, OsStr::new("file"), RenameMode::NoReplace)
+                .await,
+            Err(DbError::Exists(..))
+        ));
+        db.connection.close().await;
+
+        // Opening again has nothing to do
+        std::fs::remove_file(format!("{}.v1.bak", path))?;
+        FsDatabase::new(&path).await?.connection.close().await;
+        assert!(!std::path::Path::new(&format!("{}.v1.bak", path)).exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_newer_database_refused() -> TestResult {
+        let dir = TempDir::new("newer");
+        let path = db_path(&dir);
+        FsDatabase::new(&path).await?.connection.close().await;
+        {
+            let pool = pool(&path).await?;
+            sqlx::query("update schema_version set version=?")
+                .bind(latest_version() + 1)
+                .execute(&pool)
+                .await?;
+            pool.close().await;
+        }
+        assert!(matches!(
+            FsDatabase::new(&path).await,
+            Err(DbError::SchemaTooNew(version)) if version == latest_version() + 1
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,10 @@
+create table chunk (
+    node integer not null,
+    idx integer not null,
+    message_id text not null,
+    attachment_id text not null,
+    size integer not null,
+    cipher_size integer not null,
+    primary key(node, idx),
+    foreign key(node) references node(id) on delete cascade
+);
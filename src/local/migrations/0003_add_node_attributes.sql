@@ -0,0 +1,4 @@
+alter table node add column mode integer;
+alter table node add column uid integer;
+alter table node add column gid integer;
+alter table node add column mtime float;
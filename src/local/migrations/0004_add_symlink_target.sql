@@ -0,0 +1 @@
+alter table node add column target blob;
@@ -0,0 +1,46 @@
+create table dirent (
+    id integer primary key,
+    parent integer not null,
+    name text not null,
+    node integer not null,
+    foreign key(parent) references node(id) on delete cascade,
+    foreign key(node) references node(id) on delete cascade
+);
+
+insert into dirent (parent, name, node) select parent, name, id from node where parent is not null;
+
+create index dirent_node on dirent(node);
+
+create table inode (
+    id integer primary key,
+    size integer,
+    ctime float,
+    atime float,
+    directory boolean not null,
+    cloud_id text,
+    mode integer,
+    uid integer,
+    gid integer,
+    mtime float,
+    target blob
+);
+
+insert into inode select id, size, ctime, atime, directory, cloud_id, mode, uid, gid, mtime, target from node;
+
+drop table node;
+
+alter table inode rename to node;
+
+create view entry as
+select
+    node.*,
+    dirent.parent as parent,
+    dirent.name as name,
+    case
+        when node.directory then 2 + (
+            select count(*) from dirent child join node child_node on child.node=child_node.id
+            where child.parent=node.id and child_node.directory
+        )
+        else (select count(*) from dirent link where link.node=node.id)
+    end as nlink
+from node left join dirent on dirent.node=node.id;
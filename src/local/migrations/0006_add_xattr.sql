@@ -0,0 +1,9 @@
+create table xattr (
+    node integer not null,
+    name text not null,
+    value blob not null,
+    primary key (node, name),
+    foreign key(node) references node(id) on delete cascade
+);
+
+alter table node add column sha256 text;
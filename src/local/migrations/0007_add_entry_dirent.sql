@@ -0,0 +1,16 @@
+drop view entry;
+
+create view entry as
+select
+    node.*,
+    dirent.id as dirent,
+    dirent.parent as parent,
+    dirent.name as name,
+    case
+        when node.directory then 2 + (
+            select count(*) from dirent child join node child_node on child.node=child_node.id
+            where child.parent=node.id and child_node.directory
+        )
+        else (select count(*) from dirent link where link.node=node.id)
+    end as nlink
+from node left join dirent on dirent.node=node.id;
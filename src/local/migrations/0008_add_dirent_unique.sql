@@ -0,0 +1,8 @@
+-- Names created by racing inserts before the constraint existed keep their entry id as a suffix
+update dirent set name = name || ' (' || id || ')'
+where exists (
+    select 1 from dirent first
+    where first.parent = dirent.parent and first.name = dirent.name and first.id < dirent.id
+);
+
+create unique index dirent_name on dirent(parent, name);
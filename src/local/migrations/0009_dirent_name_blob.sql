@@ -0,0 +1,36 @@
+-- Names are raw bytes since Linux filenames don't have to be valid UTF-8
+drop view entry;
+
+create table dirent_blob (
+    id integer primary key,
+    parent integer not null,
+    name blob not null,
+    node integer not null,
+    foreign key(parent) references node(id) on delete cascade,
+    foreign key(node) references node(id) on delete cascade
+);
+
+insert into dirent_blob select id, parent, cast(name as blob), node from dirent;
+
+drop table dirent;
+
+alter table dirent_blob rename to dirent;
+
+create index dirent_node on dirent(node);
+
+create unique index dirent_name on dirent(parent, name);
+
+create view entry as
+select
+    node.*,
+    dirent.id as dirent,
+    dirent.parent as parent,
+    dirent.name as name,
+    case
+        when node.directory then 2 + (
+            select count(*) from dirent child join node child_node on child.node=child_node.id
+            where child.parent=node.id and child_node.directory
+        )
+        else (select count(*) from dirent link where link.node=node.id)
+    end as nlink
+from node left join dirent on dirent.node=node.id;
@@ -0,0 +1,39 @@
+-- Ids of deleted nodes are never handed out again, so uploads still running for a deleted node
+-- can't land on a new one
+drop view entry;
+
+create table node_autoincrement (
+    id integer primary key autoincrement,
+    size integer,
+    ctime float,
+    atime float,
+    directory boolean not null,
+    cloud_id text,
+    mode integer,
+    uid integer,
+    gid integer,
+    mtime float,
+    target blob,
+    sha256 text
+);
+
+insert into node_autoincrement select id, size, ctime, atime, directory, cloud_id, mode, uid, gid, mtime, target, sha256 from node;
+
+drop table node;
+
+alter table node_autoincrement rename to node;
+
+create view entry as
+select
+    node.*,
+    dirent.id as dirent,
+    dirent.parent as parent,
+    dirent.name as name,
+    case
+        when node.directory then 2 + (
+            select count(*) from dirent child join node child_node on child.node=child_node.id
+            where child.parent=node.id and child_node.directory
+        )
+        else (select count(*) from dirent link where link.node=node.id)
+    end as nlink
+from node left join dirent on dirent.node=node.id;
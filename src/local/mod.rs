@@ -2,3 +2,8 @@ pub mod cli;
 pub mod db;
 pub mod error;
 pub mod fuse;
+pub mod handle;
+pub mod lock;
+pub mod migrate;
+pub mod remove;
+pub mod usage;
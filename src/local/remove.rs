@@ -0,0 +1,55 @@
+use std::path::{Component, Path};
+
+use log::info;
+
+use crate::client::client::CloudClient;
+
+use super::{
+    db::FsDatabase,
+    error::{DbError, FsError},
+};
+
+/// Recursively deletes the entry at `path`, relative to the root of the filesystem,
+/// and the uploaded content of every node that is left without a name
+pub async fn remove_path(
+    db: &FsDatabase,
+    client: &dyn CloudClient,
+    path: &Path,
+) -> Result<(), FsError> {
+    let mut components = vec![];
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => components.push(name),
+            Component::RootDir | Component::CurDir => {}
+            _ => {
+                return Err(FsError::RuntimeError(format!(
+                    "path has to be relative to the root of the filesystem: {:?}",
+                    path
+                )))
+            }
+        }
+    }
+    let Some(name) = components.pop() else {
+        return Err(FsError::RuntimeError(
+            "refusing to remove the root directory".to_string(),
+        ));
+    };
+    let mut parent = 1;
+    for component in components {
+        match db.get_node(parent as u64, component).await? {
+            Some(node) if node.directory => parent = node.id,
+            Some(node) => return Err(DbError::DoesNotExist(node.id).into()),
+            None => return Err(DbError::DoesNotExist(parent).into()),
+        }
+    }
+
+    // Content of nodes uploaded before chunks were tracked would otherwise be missed
+    client.migrate().await?;
+    // Messages go first so a failure leaves the entries in place to try again,
+    // rather than content nothing refers to anymore
+    let chunks = db.tree_chunks(parent, name).await?;
+    info!("removing {:?}, deleting {} chunks", path, chunks.len());
+    client.delete_chunks(&chunks).await?;
+    db.delete_tree(parent, name).await?;
+    Ok(())
+}
@@ -0,0 +1,76 @@
+use std::sync::Mutex;
+
+use super::error::FsError;
+
+/// Bytes taken by file content as the mount shows it, counting files still open for writing
+/// and closed ones waiting for their upload along with what is committed.
+/// Kept in memory so a write claims its growth without asking the database, and claims are
+/// checked and taken in one step so concurrent writers can't go past the capacity together
+pub struct Usage {
+    used: Mutex<u64>,
+    /// Advertised size, enforced when set
+    capacity: Option<u64>,
+}
+
+impl Usage {
+    pub fn new(used: u64, capacity: Option<u64>) -> Self {
+        Self {
+            used: Mutex::new(used),
+            capacity,
+        }
+    }
+
+    pub fn used(&self) -> u64 {
+        *self.used.lock().unwrap()
+    }
+
+    pub fn capacity(&self) -> Option<u64> {
+        self.capacity
+    }
+
+    /// Claims `growth` more bytes, failing if they would go over the capacity
+    pub fn reserve(&self, growth: u64) -> Result<(), FsError> {
+        let mut used = self.used.lock().unwrap();
+        if let Some(capacity) = self.capacity {
+            if growth > 0 && used.saturating_add(growth) > capacity {
+                return Err(FsError::NoSpace);
+            }
+        }
+        *used += growth;
+        Ok(())
+    }
+
+    /// Records content that was counted as `from` bytes taking up `to` bytes instead,
+    /// for settling a claim or giving back space
+    pub fn adjust(&self, from: u64, to: u64) {
+        let mut used = self.used.lock().unwrap();
+        *used = used.saturating_add(to).saturating_sub(from);
+    }
+
+    pub fn release(&self, bytes: u64) {
+        self.adjust(bytes, 0);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_reserve() {
+        let usage = Usage::new(6, Some(10));
+        usage.reserve(4).unwrap();
+        assert!(matches!(usage.reserve(1), Err(FsError::NoSpace)));
+        // Claims that turn out smaller give the rest back
+        usage.adjust(4, 1);
+        assert_eq!(usage.used(), 7);
+        usage.reserve(3).unwrap();
+        usage.release(5);
+        assert_eq!(usage.used(), 5);
+
+        // Without a capacity only the count is kept
+        let usage = Usage::new(0, None);
+        usage.reserve(u64::MAX).unwrap();
+        assert_eq!(usage.used(), u64::MAX);
+    }
+}
@@ -4,14 +4,18 @@ pub mod error;
 pub mod local;
 pub mod util;
 
-use std::error::Error;
+use std::{error::Error, sync::Arc};
 
 use clap::Parser;
+use client::{client::ClientOptions, discord::client::DiscordClient};
 use fuser::MountOption;
-use local::{db::FsDatabase, fuse::DiscFs};
+use local::{db::FsDatabase, fuse::DiscFs, remove::remove_path};
 use log::{debug, info, LevelFilter};
 
-use crate::local::{cli::Cli, fuse::CloudType};
+use crate::local::{
+    cli::{Cli, Command},
+    fuse::CloudType,
+};
 
 fn main() -> Result<(), Box<dyn Error>> {
     let cli = Cli::parse();
@@ -33,7 +37,34 @@ fn main() -> Result<(), Box<dyn Error>> {
     let rt = tokio::runtime::Runtime::new()?;
 
     let fs_database = rt.block_on(async { FsDatabase::new(&cli.db_path).await })?;
-    let fs = DiscFs::new(rt.handle().to_owned(), fs_database, CloudType::Discord)?;
+    let client_options = ClientOptions {
+        staging_dir: cli.staging_dir(),
+        cache_dir: cli.cache_dir.clone(),
+        cache_size: cli.cache_size,
+        read_ahead: cli.read_ahead,
+        read_ahead_memory: cli.read_ahead_memory,
+        upload_parallelism: cli.upload_parallelism,
+        retry_attempts: cli.retry_attempts,
+    };
+
+    if let Some(Command::Rm { path }) = &cli.command {
+        let db = Arc::new(fs_database);
+        let client = DiscordClient::new(rt.handle().to_owned(), db.clone(), &client_options)?;
+        rt.block_on(async { remove_path(&db, &client, path).await })?;
+        return Ok(());
+    }
+
+    // Clap requires the mountpoint whenever there is no subcommand
+    let Some(mountpoint) = cli.mountpoint else {
+        return Ok(());
+    };
+    let fs = DiscFs::new(
+        rt.handle().to_owned(),
+        fs_database,
+        CloudType::Discord,
+        client_options,
+        cli.capacity,
+    )?;
     let mount_options = [
         MountOption::NoDev,
         MountOption::NoSuid,
@@ -45,7 +76,7 @@ fn main() -> Result<(), Box<dyn Error>> {
     ];
 
     rt.block_on(async {
-        let _ = fuser::mount2(fs, cli.mountpoint, &mount_options);
+        let _ = fuser::mount2(fs, mountpoint, &mount_options);
     });
 
     Ok(())
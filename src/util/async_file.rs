@@ -1,3 +1,5 @@
+use std::io::SeekFrom;
+
 use async_trait::async_trait;
 
 #[async_trait]
@@ -10,3 +12,10 @@ pub trait AsyncWrite {
 pub trait AsyncRead {
     async fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize>;
 }
+
+#[async_trait]
+pub trait AsyncSeek {
+    /// Moves the cursor used by the next read or write.
+    /// Returns the new position from the start of the file
+    async fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64>;
+}
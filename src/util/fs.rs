@@ -1,34 +1,47 @@
 use fuser::{FileAttr, FileType};
 
-use crate::local::{db::FsNode, error::FsError};
+use crate::local::{
+    db::{FsNode, NodeKind},
+    error::FsError,
+};
 
 use super::time::float_to_time;
 
 pub fn attrs_from_node(node: &FsNode) -> Result<FileAttr, FsError> {
     let ctime = float_to_time(node.ctime.unwrap_or(0.0))?;
+    let mtime = match node.mtime {
+        Some(mtime) => float_to_time(mtime)?,
+        None => ctime,
+    };
     Ok(FileAttr {
         ino: node.id as u64,
         size: node.size.unwrap_or(0) as u64,
         blocks: 0,
         atime: float_to_time(node.atime.unwrap_or(0.0))?,
-        mtime: ctime,
+        mtime,
         ctime,
         crtime: ctime,
-        kind: if node.directory {
-            FileType::Directory
-        } else {
-            FileType::RegularFile
+        kind: kind_from_node(node),
+        // Nodes created before permissions were stored
+        perm: match (node.mode, node.kind()) {
+            (_, NodeKind::Symlink) => 0o777,
+            (Some(mode), _) => (mode & 0o7777) as u16,
+            (None, NodeKind::Directory) => 0o777,
+            (None, _) => 0o444,
         },
-        perm: if node.directory {
-            0b111111111
-        } else {
-            0b100100100
-        },
-        nlink: 1,
-        uid: 0,
-        gid: 0,
+        nlink: node.nlink as u32,
+        uid: node.uid.unwrap_or(0) as u32,
+        gid: node.gid.unwrap_or(0) as u32,
         rdev: 0,
         blksize: 0,
         flags: 0,
     })
 }
+
+pub fn kind_from_node(node: &FsNode) -> FileType {
+    match node.kind() {
+        NodeKind::File => FileType::RegularFile,
+        NodeKind::Directory => FileType::Directory,
+        NodeKind::Symlink => FileType::Symlink,
+    }
+}
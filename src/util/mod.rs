@@ -1,3 +1,5 @@
 pub mod async_file;
 pub mod fs;
+#[cfg(test)]
+pub mod test;
 pub mod time;
@@ -0,0 +1,145 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use tokio::sync::{Mutex, Notify};
+
+use crate::{
+    client::{
+        discord::staging::{ChunkStore, Staging},
+        error::ClientError,
+    },
+    local::{
+        db::{FsChunk, FsDatabase, FsNode},
+        error::FsError,
+    },
+};
+
+/// Directory in the temp dir, removed when dropped
+pub struct TempDir(pub PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("discfs-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Keeps uploaded chunks in memory, with chunks of 4 bytes
+pub struct MemoryStore {
+    pub db: FsDatabase,
+    pub staging: Staging,
+    dir: PathBuf,
+    /// Content of every chunk by attachment id
+    pub messages: std::sync::Mutex<HashMap<String, Vec<u8>>>,
+    pub uploads: AtomicUsize,
+    pub downloads: AtomicUsize,
+    /// Uploads running at the same time, now and at most
+    pub active: AtomicUsize,
+    pub peak: AtomicUsize,
+    pub fail: AtomicBool,
+    /// Held by a test to stop uploads until it has changed the file
+    pub gate: Mutex<()>,
+    pub started: Notify,
+}
+
+impl MemoryStore {
+    pub async fn new(dir: &Path) -> Result<Arc<Self>, Box<dyn Error>> {
+        Ok(Arc::new(Self {
+            db: FsDatabase::new(":memory:").await?,
+            staging: Staging::new(dir.to_path_buf(), 4, 2)?,
+            dir: dir.to_path_buf(),
+            messages: std::sync::Mutex::new(HashMap::new()),
+            uploads: AtomicUsize::new(0),
+            downloads: AtomicUsize::new(0),
+            active: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+            fail: AtomicBool::new(false),
+            gate: Mutex::new(()),
+            started: Notify::new(),
+        }))
+    }
+
+    /// Same uploaded chunks and database with a new staging directory, as after a restart
+    pub fn restart(self: Arc<Self>) -> Arc<Self> {
+        let store = Arc::try_unwrap(self).ok().unwrap();
+        Arc::new(Self {
+            staging: Staging::new(store.dir.clone(), 4, 2).unwrap(),
+            ..store
+        })
+    }
+}
+
+#[async_trait]
+impl ChunkStore for MemoryStore {
+    async fn get_chunks(&self, node: &FsNode) -> Result<Vec<FsChunk>, FsError> {
+        Ok(self.db.get_chunks(node.id).await?)
+    }
+
+    async fn download_chunk(&self, chunk: &FsChunk) -> Result<Vec<u8>, ClientError> {
+        self.downloads.fetch_add(1, Ordering::SeqCst);
+        let messages = self.messages.lock().unwrap();
+        Ok(messages[&chunk.attachment_id].clone())
+    }
+
+    async fn upload_chunk(
+        &self,
+        node: i64,
+        idx: usize,
+        data: &[u8],
+    ) -> Result<FsChunk, ClientError> {
+        self.started.notify_one();
+        drop(self.gate.lock().await);
+        let active = self.active.fetch_add(1, Ordering::SeqCst) + 1;
+        self.peak.fetch_max(active, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        self.active.fetch_sub(1, Ordering::SeqCst);
+        if self.fail.load(Ordering::SeqCst) {
+            return Err(ClientError::RequestValue("upload failed".to_string()));
+        }
+        let id = self.uploads.fetch_add(1, Ordering::SeqCst).to_string();
+        self.messages
+            .lock()
+            .unwrap()
+            .insert(id.clone(), data.to_vec());
+        Ok(FsChunk {
+            node,
+            idx: idx as i64,
+            message_id: id.clone(),
+            attachment_id: id,
+            size: data.len() as i64,
+            cipher_size: data.len() as i64,
+        })
+    }
+
+    async fn discard_chunks(&self, chunks: &[FsChunk]) {
+        let mut messages = self.messages.lock().unwrap();
+        for chunk in chunks {
+            messages.remove(&chunk.attachment_id);
+        }
+    }
+
+    fn db(&self) -> &FsDatabase {
+        &self.db
+    }
+
+    fn staging(&self) -> &Staging {
+        &self.staging
+    }
+}